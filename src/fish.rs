@@ -2,8 +2,14 @@ use reqwest;
 use serde;
 use serde_json;
 use time;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
 
 const RIVNEFISHURL: &str = "https://rivnefish.com/api/v1";
+const VOTES_BATCH_SIZE: usize = 50;
+const VOTES_ATTEMPTS: u32 = 3;
 
 #[derive(Deserialize)]
 pub struct RfReportPhoto {
@@ -12,10 +18,10 @@ pub struct RfReportPhoto {
 
 #[derive(Deserialize)]
 pub struct RfFishingType {
-    name: String,
+    pub name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RfFish {
     id: u32,
     name: String,
@@ -35,6 +41,8 @@ pub struct RfReportInfo {
     pub id: i32,
     pub title: String,
     pub short_description: String,
+    #[serde(default)]
+    pub description: String,
     pub url: String,
     pub place: Option<RfPlace>,
     pub photos: Vec<RfReportPhoto>,
@@ -46,6 +54,11 @@ pub struct RfReportInfo {
 }
 
 #[derive(Deserialize)]
+pub struct RfReportBrief {
+    pub id: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RfPlace {
     pub name: String,
     pub id: i32,
@@ -76,7 +89,7 @@ pub struct RfPlaceInfoRaw {
     pub id: i32,
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RfPlaceInfo {
     pub name: String,
     pub thumbnail: String,
@@ -95,14 +108,28 @@ pub struct RfPlaceInfo {
     pub id: i32,
 }
 
+#[derive(Serialize)]
+pub struct RfReportVotes {
+    pub report_id: i32,
+    pub votes: BTreeMap<String, usize>, // by reaction key
+}
+
+#[derive(Serialize)]
+struct RfVotesBatch<'a> {
+    reports: &'a [RfReportVotes],
+}
+
+// Delay after failed attempt, tests don't wait for real.
 pub struct RfApi {
     http_client: reqwest::Client,
+    retry_delay: Duration, // before second attempt, doubled after each next one
 }
 
 impl RfApi {
     pub fn new() -> RfApi {
         RfApi {
             http_client: reqwest::Client::new(),
+            retry_delay: Duration::from_secs(2),
         }
     }
 
@@ -115,32 +142,44 @@ impl RfApi {
             )
     }
 
-    pub fn fetch_all_fish(&self) -> Vec<RfFish> {
+    pub fn fetch_all_fish(&self) -> Option<Vec<RfFish>> {
         let url = format!("{}/{}", RIVNEFISHURL, "fish");
 
         match self.fetch::<Vec<RfFish>>(&url) {
             Ok(fs) => {
                 info!("fetched {} kinds of fish", fs.len());
-                fs
+                Some(fs)
             },
             Err(e) => {
                 error!("fetching fish kinds: {}", e);
-                Vec::new()
+                None
             },
         }
     }
 
-    pub fn fetch_all_places(&self) -> Vec<RfPlace> {
+    pub fn fetch_all_places(&self) -> Option<Vec<RfPlace>> {
         let url = format!("{}/{}", RIVNEFISHURL, "places");
 
         match self.fetch::<Vec<RfPlace>>(&url) {
             Ok(ps) => {
                 info!("fetched {} places", ps.len());
-                ps
+                Some(ps)
             },
             Err(e) => {
                 error!("fetching places: {}", e);
-                Vec::new()
+                None
+            },
+        }
+    }
+
+    pub fn fetch_recent_reports(&self) -> Option<Vec<RfReportBrief>> {
+        let url = format!("{}/{}", RIVNEFISHURL, "reports");
+
+        match self.fetch::<Vec<RfReportBrief>>(&url) {
+            Ok(rs) => Some(rs),
+            Err(e) => {
+                error!("fetching recent reports: {}", e);
+                None
             },
         }
     }
@@ -157,6 +196,52 @@ impl RfApi {
         }
     }
 
+    fn post_votes_batch(&self, url: &str, token: &str, batch: &[RfReportVotes]) -> Result<(), String> {
+        let bod = serde_json::to_string(&RfVotesBatch { reports: batch }).map_err(|e| e.to_string())?;
+        let mut req = self.http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(bod);
+        if !token.is_empty() {
+            req = req.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        match req.send() {
+            Ok(ref r) if r.status().is_success() => Ok(()),
+            Ok(r) => Err(format!("site responded {}", r.status())),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Sends votes in batches, each batch is retried with growing delay.
+    pub fn push_votes(&self, url: &str, token: &str, votes: &[RfReportVotes]) -> Result<(), String> {
+        for batch in votes.chunks(VOTES_BATCH_SIZE) {
+            let mut attempt = 1;
+            loop {
+                match self.post_votes_batch(url, token, batch) {
+                    Ok(_) => break,
+                    Err(e) if attempt < VOTES_ATTEMPTS => {
+                        warn!("pushing votes (attempt {}): {}", attempt, e);
+                        thread::sleep(self.retry_delay * (1 << (attempt - 1)));
+                        attempt += 1;
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        info!("pushed votes for {} reports", votes.len());
+        Ok(())
+    }
+
+    pub fn fetch_photo(&self, url: &str) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        self.http_client.get(url).send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|mut r| r.copy_to(&mut buf))
+            .map_err(|e| e.to_string())
+            .map(|_| buf)
+    }
+
     pub fn fetch_report_info(&self, reportid: i32) -> Option<RfReportInfo> {
         let url = format!("{}/{}/{}", RIVNEFISHURL, "reports", reportid);
 
@@ -268,7 +353,32 @@ fn build_fish_entry(x: &RfFishReport, fishes: &[RfFish]) -> String {
     result
 }
 
-pub fn get_report_text(report: &RfReportInfo, place: Option<&RfPlaceInfo>, fishes: &[RfFish]) -> String {
+// One line per report for digest: title linking to report, place and
+// up to three biggest (or featured) catches.
+pub fn get_digest_entry(report: &RfReportInfo, fishes: &[RfFish]) -> String {
+    let mut catches = report.report_fishes.iter()
+        .filter(|x| x.weight.is_some() || x.featured)
+        .collect::<Vec<_>>();
+    catches.sort_by(|a, b| (b.featured, b.weight.unwrap_or(0.0))
+        .partial_cmp(&(a.featured, a.weight.unwrap_or(0.0)))
+        .unwrap_or(Ordering::Equal));
+
+    let catches = catches.iter().take(3).map(|x| format!("{}{}{}",
+        fishes.iter().find(|f| f.id == x.fish_id).map(|f| f.name.as_str()).unwrap_or("?"),
+        x.weight.map(|n| format!(" {}кг", n)).unwrap_or_default(),
+        if x.featured {" &#x1F3C6"} else {""},
+    )).collect::<Vec<_>>();
+
+    format!("&#x2022 <a href=\"{u}\">{t}</a>{p}{c}",
+        u = report.url,
+        t = report.title,
+        p = report.place.as_ref().map(|p| format!(" \u{2014} <i>{}</i>", p.name)).unwrap_or_default(),
+        c = if catches.is_empty() { String::new() } else { format!("\n    {}", catches.join(", ")) },
+    )
+}
+
+// With `article` set, link preview shows Telegraph page instead of featured image.
+pub fn get_report_text(report: &RfReportInfo, place: Option<&RfPlaceInfo>, fishes: &[RfFish], article: Option<&str>) -> String {
     let mut results = report.report_fishes.iter()
         .map(|r| build_fish_entry(r, fishes))
         .collect::<Vec<_>>();
@@ -290,9 +400,9 @@ r#"<b>{t}</b>{fi}
 <b>Тип рибалки:</b> {f}
 <b>Спіймана риба:</b>{fs}
 
-<i>{s}</i>"#,
+<i>{s}</i>{a}"#,
         t = report.title,
-        fi = report.featured_image.as_ref()
+        fi = article.or(report.featured_image.as_ref().map(|s| s.as_str()))
             .map(|s| format!("<a href=\"{}\">&#160;</a>", s))
             .unwrap_or_default(),
         p = place.map(|p| format!(
@@ -307,5 +417,50 @@ r#"<b>{t}</b>{fi}
             |mut acc, x| { acc.push_str(&format!("\n&#x2022 {}", x)); acc }
         ),
         s = report.short_description.trim(),
+        a = article.map(|s| format!("\n\n&#x1F4D6 <a href=\"{}\">читати повністю</a>", s))
+            .unwrap_or_default(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil;
+
+    fn votes(n: i32) -> Vec<RfReportVotes> {
+        (0..n).map(|id| RfReportVotes {
+            report_id: id,
+            votes: vec![("like".to_owned(), 1)].into_iter().collect(),
+        }).collect()
+    }
+
+    fn quick_retries() -> RfApi {
+        RfApi {
+            retry_delay: Duration::from_millis(1),
+            ..RfApi::new()
+        }
+    }
+
+    fn batch_len(body: &str) -> usize {
+        serde_json::from_str::<serde_json::Value>(body).unwrap()["reports"].as_array().unwrap().len()
+    }
+
+    #[test]
+    fn push_votes_in_batches_with_retry() {
+        let (url, server) = testutil::serve(vec![(500, ""), (200, ""), (200, ""), (200, "")]);
+
+        assert_eq!(quick_retries().push_votes(&url, "token", &votes(120)), Ok(()));
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.iter().map(|b| batch_len(b)).collect::<Vec<_>>(), vec![50, 50, 50, 20]);
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[test]
+    fn push_votes_gives_up() {
+        let (url, server) = testutil::serve(vec![(500, ""); VOTES_ATTEMPTS as usize]);
+
+        assert_eq!(quick_retries().push_votes(&url, "", &votes(60)), Err("site responded 500 Internal Server Error".to_owned()));
+        assert_eq!(server.join().unwrap().len(), VOTES_ATTEMPTS as usize);
+    }
+}
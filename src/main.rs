@@ -13,24 +13,49 @@ extern crate serde_json;
 extern crate reqwest;
 #[macro_use]
 extern crate lazy_static;
+extern crate rusqlite;
+extern crate ctrlc;
+extern crate hmac_sha256;
 
 use iron::prelude::*;
 use iron::typemap::Key;
+use iron::Handler;
 
 use persistent::{Read, State};
 
 use time::PreciseTime;
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::io::Write;
 use std::collections::hash_map::{HashMap, Entry};
+use std::collections::{BTreeMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[allow(dead_code)]
 mod telegram;
 use telegram::*;
 
 mod fish;
-use fish::{RfApi, RfPlace, RfPlaceInfo, RfFish};
+use fish::{RfApi, RfPlace, RfPlaceInfo, RfFish, RfReportVotes};
+
+mod persist;
+
+mod routing;
+use routing::Route;
+
+mod telegraph;
+
+mod publish;
+use publish::{ChatCard, ChatSent, Failure, Published, QueuedReport, RetryEntry, RetryOp, LINK_TEXT};
+use publish::{fits_caption, is_permanent_error, make_caption, make_vote_text, queue_retry, send_announcement, send_captioned, send_card};
+
+#[cfg(test)]
+mod testutil;
+
+mod storage;
+use storage::{Storage, VoteRecord, PostRecord, UserRecord};
 
 fn get_info_for(st: &SafeBotState, rfapi: &RfApi, id: i32) -> Option<RfPlaceInfo> {
     match st.read() {
@@ -52,37 +77,475 @@ fn get_info_for(st: &SafeBotState, rfapi: &RfApi, id: i32) -> Option<RfPlaceInfo
         let cache = &mut state.cache;
 
         cache.insert(id, fetched.clone());
+
+        if fetched.is_some() {
+            state.catalog_rev += 1;
+        }
     }
 
     fetched
 }
 
-fn make_cbq_data(msg: i32) -> String {
-    format!("u{}", msg)
+fn catalog_snapshot(bs: &BotState) -> Option<persist::CatalogSnapshot> {
+    bs.catalog_fetched_at.map(|fetched_at| persist::CatalogSnapshot {
+        fetched_at,
+        places: bs.places.clone(),
+        fishes: bs.fishes.clone(),
+        cache: bs.cache.iter()
+            .filter_map(|(id, pi)| pi.clone().map(|pi| (*id, pi)))
+            .collect(),
+    })
 }
 
-fn parse_cbq_data(d: &str) -> Option<i32> {
-    d.bytes().nth(0)
-        .filter(|zth| *zth == b'u')
-        .and_then(|_| i32::from_str_radix(&d[1..], 10).ok())
+// Snapshot is written outside of the lock, so inline queries and votes
+// don't wait for the disk.
+fn spawn_catalog_saver(st: SafeBotState, cfg: &'static Config) {
+    thread::spawn(move || {
+        let mut saved_rev = 0;
+        loop {
+            thread::sleep(Duration::from_secs(cfg.savedelay));
+
+            let (rev, snapshot) = match st.read() {
+                Ok(guard) if guard.catalog_rev != saved_rev => (guard.catalog_rev, catalog_snapshot(&guard)),
+                _ => continue,
+            };
+
+            match snapshot.map_or(Ok(()), |s| persist::save_json(&cfg.snapshot, &s)) {
+                Ok(_) => saved_rev = rev,
+                Err(e) => error!("saving catalog snapshot to {}: {}", cfg.snapshot, e),
+            }
+        }
+    });
 }
 
-fn make_report_kb(kbdata: &KbData, message_id: i32) -> TgInlineKeyboardMarkup {
-    TgInlineKeyboardMarkup { inline_keyboard: vec![vec![
-        TgInlineKeyboardButton::Cb {
-            text: format!("\u{1F44D} {}", kbdata.votes.len()),
-            callback_data: make_cbq_data(message_id),
+fn load_catalog(bs: &mut BotState, path: &str) {
+    match persist::load_json::<persist::CatalogSnapshot>(path) {
+        Ok(snapshot) => {
+            bs.places = snapshot.places;
+            bs.fishes = snapshot.fishes;
+            bs.cache = snapshot.cache.into_iter().map(|(id, pi)| (id, Some(pi))).collect();
+            bs.catalog_fetched_at = Some(snapshot.fetched_at);
+            info!(
+                "loaded catalog snapshot: {} places, {} kinds of fish, {} cached, {}s old",
+                bs.places.len(),
+                bs.fishes.len(),
+                bs.cache.len(),
+                catalog_age(bs).unwrap_or_default()
+            );
         },
-        TgInlineKeyboardButton::Url {
-            text: "переглянути на вебсайті".to_owned(),
-            url: kbdata.url.clone(),
+        Err(e) => warn!("no catalog snapshot loaded from {}: {}", path, e),
+    }
+}
+
+fn catalog_age(bs: &BotState) -> Option<i64> {
+    bs.catalog_fetched_at.map(|t| time::get_time().sec - t)
+}
+
+// Posts older than `cfg.retention` days stop accepting votes: they are
+// appended to archive file, removed from `kbdata` and get a frozen keyboard.
+fn archive_old_posts(st: &SafeBotState, cfg: &Config) {
+    let cutoff = time::get_time().sec - cfg.retention * 24 * 60 * 60;
+    if !st.read().map(|g| g.kbdata.values().any(|kb| kb.published_at < cutoff)).unwrap_or(false) {
+        return;
+    }
+
+    let mut archive = if std::path::Path::new(&cfg.archive).exists() {
+        match persist::load_archive(&cfg.archive) {
+            Ok(archive) => archive,
+            Err(e) => {
+                error!("loading archive from {}: {}", cfg.archive, e);
+                return;
+            },
+        }
+    } else {
+        HashMap::new()
+    };
+
+    // taken out in one go, so no vote lands between archiving and removal
+    let expired: HashMap<PostId, KbData> = match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+            let ids = bs.kbdata.iter()
+                .filter(|&(_, kb)| kb.published_at < cutoff)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            for id in &ids {
+                bs.kb_edits.remove(id);
+            }
+            if !ids.is_empty() {
+                bs.state_rev += 1;
+            }
+            ids.into_iter().filter_map(|id| bs.kbdata.remove(&id).map(|kb| (id, kb))).collect()
+        },
+        Err(_) => return,
+    };
+
+    if expired.is_empty() {
+        return;
+    }
+
+    archive.extend(expired.iter().map(|(id, kb)| (*id, kb.clone())));
+    if let Err(e) = persist::save_json(&cfg.archive, &archive) {
+        error!("saving archive to {}: {}", cfg.archive, e);
+        // keep accepting votes until archive can be written
+        if let Ok(mut guard) = st.write() {
+            let bs = &mut *guard;
+            for (id, kb) in expired {
+                bs.kbdata.entry(id).or_insert(kb);
+            }
+            bs.state_rev += 1;
+        }
+        return;
+    }
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    for (id, kb) in &expired {
+        tg.update_kb(*id, make_frozen_kb(kb), TgChatId::Username(cfg.channel.clone()));
+    }
+
+    info!("archived {} posts older than {} days", expired.len(), cfg.retention);
+}
+
+// Place cards shared via inline mode expire the same way, but they are not
+// reports, so their votes are dropped instead of archived.
+fn expire_inline_cards(st: &SafeBotState, cfg: &Config) {
+    let cutoff = time::get_time().sec - cfg.retention * 24 * 60 * 60;
+    let expired = match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+            let ids = bs.inline_kbdata.iter()
+                .filter(|&(_, ikb)| ikb.kbdata.published_at < cutoff)
+                .map(|(imi, _)| imi.clone())
+                .collect::<Vec<_>>();
+            for imi in &ids {
+                bs.inline_kb_edits.remove(imi);
+            }
+            if !ids.is_empty() {
+                bs.state_rev += 1;
+            }
+            ids.into_iter().filter_map(|imi| bs.inline_kbdata.remove(&imi).map(|ikb| (imi, ikb))).collect::<Vec<_>>()
+        },
+        Err(_) => return,
+    };
+
+    if expired.is_empty() {
+        return;
+    }
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    for (imi, ikb) in &expired {
+        tg.update_inline_kb(imi.clone(), make_frozen_kb(&ikb.kbdata));
+    }
+
+    info!("expired {} inline place cards older than {} days", expired.len(), cfg.retention);
+}
+
+// Votes only mark keyboard as outdated, it is edited once it stays
+// outdated for `cfg.kbeditdelay` ms, so a burst of votes makes one edit.
+fn take_due<K: Clone + Eq + std::hash::Hash>(edits: &mut HashMap<K, Instant>, now: Instant, delay: Duration) -> Vec<K> {
+    let ids = edits.iter()
+        .filter(|&(_, t)| now.duration_since(*t) >= delay)
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+    for id in &ids {
+        edits.remove(id);
+    }
+    ids
+}
+
+fn flush_kb_edits(st: &SafeBotState, cfg: &Config, delay: Duration) {
+    let now = Instant::now();
+    let (due, inline_due) = match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+
+            let cooldown = Duration::from_millis(cfg.votecooldown);
+            bs.last_votes.retain(|_, t| now.duration_since(*t) < cooldown);
+
+            let due = take_due(&mut bs.kb_edits, now, delay).into_iter()
+                .filter_map(|id| bs.kbdata.get(&id).map(|kb| (id, make_report_kb(kb, id, cfg))))
+                .collect::<Vec<_>>();
+            let inline_due = take_due(&mut bs.inline_kb_edits, now, delay).into_iter()
+                .filter_map(|imi| bs.inline_kbdata.get(&imi)
+                    .map(|ikb| make_place_kb(&ikb.kbdata, ikb.place_id, cfg))
+                    .map(|kb| (imi, kb)))
+                .collect::<Vec<_>>();
+            (due, inline_due)
+        },
+        Err(_) => return,
+    };
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    for (id, kb) in due {
+        tg.update_kb(id, kb, TgChatId::Username(cfg.channel.clone()));
+    }
+    for (imi, kb) in inline_due {
+        tg.update_inline_kb(imi, kb);
+    }
+}
+
+fn spawn_kb_flusher(st: SafeBotState, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(200));
+        flush_kb_edits(&st, cfg, Duration::from_millis(cfg.kbeditdelay));
+    });
+}
+
+// Votes of all posts of the same report are summed up.
+fn collect_report_votes(kbdata: &HashMap<PostId, KbData>) -> Vec<RfReportVotes> {
+    let mut by_report: BTreeMap<i32, BTreeMap<String, usize>> = BTreeMap::new();
+
+    for kb in kbdata.values() {
+        if let Some(report_id) = kb.report_id {
+            let counts = by_report.entry(report_id).or_insert_with(BTreeMap::new);
+            for (reaction, users) in &kb.votes {
+                *counts.entry(reaction.clone()).or_insert(0) += users.len();
+            }
+        }
+    }
+
+    by_report.into_iter()
+        .map(|(report_id, votes)| RfReportVotes { report_id, votes })
+        .collect()
+}
+
+fn spawn_vote_sync(st: SafeBotState, cfg: &'static Config) {
+    thread::spawn(move || {
+        let rfapi = RfApi::new();
+        let mut synced_rev = 0;
+        loop {
+            thread::sleep(Duration::from_secs(cfg.votesyncperiod));
+
+            let (rev, votes) = match st.read() {
+                Ok(bs) if bs.state_rev != synced_rev => (bs.state_rev, collect_report_votes(&bs.kbdata)),
+                _ => continue,
+            };
+
+            match rfapi.push_votes(&cfg.votesyncurl, &cfg.votesynctoken, &votes) {
+                Ok(_) => synced_rev = rev,
+                Err(e) => error!("pushing votes to {}: {}", cfg.votesyncurl, e),
+            }
+        }
+    });
+}
+
+fn spawn_archiver(st: SafeBotState, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        archive_old_posts(&st, cfg);
+        expire_inline_cards(&st, cfg);
+        thread::sleep(Duration::from_secs(60 * 60));
+    });
+}
+
+fn load_state_file(bs: &mut BotState, path: &str) {
+    match persist::load_json(path).and_then(persist::upgrade_state) {
+        Ok(doc) => {
+            doc.apply_to(bs);
+            info!("loaded {} vote keyboards from {}", bs.kbdata.len(), path);
         },
-    ]]}
+        Err(e) => warn!("no state loaded from {}: {}", path, e),
+    }
+}
+
+// Changes are coalesced: state is written at most once per `delay` seconds,
+// and only if `state_rev` moved since the last successful write.
+fn spawn_state_saver(st: SafeBotState, path: String, delay: u64) {
+    thread::spawn(move || {
+        let mut saved_rev = 0;
+        loop {
+            thread::sleep(Duration::from_secs(delay));
+
+            let snapshot = match st.read() {
+                Ok(bs) if bs.state_rev != saved_rev => Some((persist::StateDoc::from_state(&bs), bs.state_rev, bs.kbdata.len())),
+                _ => None,
+            };
+
+            if let Some((doc, rev, kbs)) = snapshot {
+                match persist::save_json(&path, &doc) {
+                    Ok(_) => {
+                        debug!("saved {} vote keyboards to {}", kbs, path);
+                        saved_rev = rev;
+                    },
+                    Err(e) => error!("saving state to {}: {}", path, e),
+                }
+            }
+        }
+    });
+}
+
+// (key, emoji) of reactions offered under published reports.
+// Keys end up in callback data and in saved state, never reuse them.
+const REACTIONS: &[(&str, &str)] = &[
+    ("like", "\u{1F44D}"),
+    ("go", "\u{1F41F}"),
+    ("trophy", "\u{1F525}"),
+];
+
+const CBQ_VERSION: &str = "v1";
+
+fn cbq_mac(payload: &str, secret: &str) -> String {
+    hmac_sha256::HMAC::mac(payload.as_bytes(), secret.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn same_mac(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Clone, Copy, Debug)]
+enum VoteTarget {
+    Post(PostId), // report published in channel
+    Place(i32),   // place card shared via inline mode
+}
+
+// `v1:<reaction>:<target>:<mac>`, where target is `<msg>` or `p<place>`,
+// and mac is truncated HMAC-SHA256 of everything before it, keyed by
+// `cfg.cbqsecret`.
+fn make_cbq_data(reaction: &str, target: VoteTarget, cfg: &Config) -> String {
+    let payload = match target {
+        VoteTarget::Post(msg) => format!("{}:{}:{}", CBQ_VERSION, reaction, msg),
+        VoteTarget::Place(id) => format!("{}:{}:p{}", CBQ_VERSION, reaction, id),
+    };
+    let mac = cbq_mac(&payload, &cfg.cbqsecret);
+    format!("{}:{}", payload, mac)
+}
+
+fn parse_reaction(d: &str) -> Option<(&'static str, VoteTarget)> {
+    let mut it = d.splitn(2, ':');
+    let target = |t: &str| if t.starts_with('p') {
+        t[1..].parse().ok().map(VoteTarget::Place)
+    } else {
+        t.parse().ok().map(VoteTarget::Post)
+    };
+
+    match (it.next(), it.next()) {
+        (Some(r), Some(t)) => REACTIONS.iter()
+            .find(|&&(key, _)| key == r)
+            .and_then(|&(key, _)| target(t).map(|t| (key, t))),
+        _ => None,
+    }
+}
+
+// Unsigned `<reaction>:<msg>` and `u<msg>` (the latter means "like") are
+// still sitting in keyboards published earlier, they are accepted until
+// `cfg.cbqunsigneduntil`. Every vote re-renders keyboard with signed data.
+fn parse_cbq_data(d: &str, cfg: &Config) -> Option<(&'static str, VoteTarget)> {
+    let prefix = format!("{}:", CBQ_VERSION);
+    if d.starts_with(&prefix) {
+        let signed = &d[prefix.len()..];
+        return match signed.rfind(':') {
+            Some(i) if same_mac(&signed[i + 1..], &cbq_mac(&d[..prefix.len() + i], &cfg.cbqsecret)) => {
+                parse_reaction(&signed[..i])
+            },
+            _ => {
+                warn!("CBQ data with bad signature: {}", d);
+                None
+            },
+        };
+    }
+
+    if time::get_time().sec >= cfg.cbqunsigneduntil.unwrap_or(0) {
+        warn!("unsigned CBQ data is not accepted anymore: {}", d);
+        return None;
+    }
+
+    if d.starts_with('u') {
+        d[1..].parse().ok().map(|msg| (REACTIONS[0].0, VoteTarget::Post(msg)))
+    } else {
+        match parse_reaction(d) {
+            Some((_, VoteTarget::Place(_))) => None,
+            r => r,
+        }
+    }
+}
+
+fn make_vote_kb(kbdata: &KbData, target: VoteTarget, link_text: &str, cfg: &Config) -> TgInlineKeyboardMarkup {
+    TgInlineKeyboardMarkup { inline_keyboard: vec![
+        REACTIONS.iter().map(|&(key, emoji)| TgInlineKeyboardButton::Cb {
+            text: format!("{} {}", emoji, kbdata.count(key)),
+            callback_data: make_cbq_data(key, target, cfg),
+        }).collect(),
+        vec![TgInlineKeyboardButton::Url {
+            text: link_text.to_owned(),
+            url: kbdata.url.clone(),
+        }],
+    ]}
+}
+
+fn make_report_kb(kbdata: &KbData, message_id: i32, cfg: &Config) -> TgInlineKeyboardMarkup {
+    make_vote_kb(kbdata, VoteTarget::Post(message_id), LINK_TEXT, cfg)
+}
+
+fn make_place_kb(kbdata: &KbData, place_id: i32, cfg: &Config) -> TgInlineKeyboardMarkup {
+    make_vote_kb(kbdata, VoteTarget::Place(place_id), "детальніше на вебсайті", cfg)
+}
+
+// Keyboard of place card shared via inline mode.
+fn make_place_card_kb(pi: &RfPlaceInfo, cfg: &Config) -> TgInlineKeyboardMarkup {
+    if cfg.inlinevotes {
+        make_place_kb(&KbData::new(pi.url.clone(), None, None), pi.id, cfg)
+    } else {
+        TgInlineKeyboardMarkup::url_button("детальніше на вебсайті".to_owned(), pi.url.clone())
+    }
+}
+
+fn votes_too_often(bs: &BotState, user: UserId, now: Instant, cfg: &Config) -> bool {
+    let cooldown = Duration::from_millis(cfg.votecooldown);
+    bs.last_votes.get(&user).map_or(false, |t| now.duration_since(*t) < cooldown)
+}
+
+// Returns true if vote was added, false if taken back.
+fn toggle_vote(kbdata: &mut KbData, reaction: &str, user: UserId) -> bool {
+    let votes = kbdata.votes.entry(reaction.to_owned()).or_insert_with(Vec::new);
+    if let Some(i) = votes.iter().position(|x| *x == user) {
+        votes.swap_remove(i);
+        false
+    } else {
+        votes.push(user);
+        true
+    }
+}
+
+fn parse_result_id(result_id: &str) -> Option<i32> {
+    if result_id.starts_with("iqid_") {
+        result_id[5..].parse().ok()
+    } else {
+        None
+    }
+}
+
+// Keyboard of a post that no longer accepts votes.
+fn make_frozen_kb(kbdata: &KbData) -> TgInlineKeyboardMarkup {
+    let counts = REACTIONS.iter()
+        .map(|&(key, emoji)| format!("{} {}", emoji, kbdata.count(key)))
+        .collect::<Vec<_>>();
+
+    TgInlineKeyboardMarkup::url_button(
+        format!("{} \u{00B7} переглянути на вебсайті", counts.join(" ")),
+        kbdata.url.clone(),
+    )
 }
 
 type SafeBotState = Arc<RwLock<<BotState as Key>::Value>>;
 
-fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config) {
+struct BotStorage;
+
+impl Key for BotStorage {
+    type Value = Box<dyn Storage>;
+}
+
+type SafeStorage = Arc<<BotStorage as Key>::Value>;
+
+fn log_db<T>(r: Result<T, String>) {
+    if let Err(e) = r {
+        error!("storage: {}", e);
+    }
+}
+
+fn process_update(st: &SafeBotState, db: &dyn Storage, upd: TgUpdate, updstr: &str, cfg: &Config) {
     let tg = TgBotApi::new(&cfg.bottoken);
     match upd {
         TgUpdate {
@@ -94,40 +557,66 @@ fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config)
             ..
         } => {
             info!("CIR: resuldid: {}, inline msg id: {}", result_id, imi);
+            if cfg.inlinevotes {
+                if let Some(pi) = parse_result_id(&result_id).and_then(|id| get_info_for(st, &RfApi::new(), id)) {
+                    if let Ok(mut guard) = st.write() {
+                        let bs = &mut *guard;
+                        bs.inline_kbdata.entry(imi).or_insert_with(|| InlineKbData {
+                            place_id: pi.id,
+                            kbdata: KbData::new(pi.url, Some(pi.name), None),
+                        });
+                        bs.state_rev += 1;
+                    }
+                }
+            }
         },
         TgUpdate {
             message: None,
             callback_query: Some(TgCallbackQuery {
                 id: cbq_id,
                 from: user,
-                message: Some(TgMessageLite{message_id, chat}),
-                data: Some(d)
+                message: Some(TgMessageLite{message_id, chat, ..}),
+                data: Some(d),
+                ..
             }),
             inline_query: None,
             ..
         } => {
             info!("CBQ: id: {} from: {} msgid: {} chat: {:?} data: {}", cbq_id, user.id, message_id, chat, d);
-            if let Some(original_message_id) = parse_cbq_data(&d) {
+            if let Some((reaction, VoteTarget::Post(original_message_id))) = parse_cbq_data(&d, cfg) {
                 if let Ok(mut guard) = st.write() {
                     let bs = &mut *guard;
+                    let now = Instant::now();
+                    if votes_too_often(bs, user.id, now, cfg) {
+                        info!("user {} votes too often", user.id);
+                        tg.answer_cbq(cbq_id, Some("зачекайте трохи".to_owned()));
+                        return;
+                    }
                     match bs.kbdata.entry(original_message_id) {
                         Entry::Occupied(mut e) => {
-                            let kbdata = e.get_mut();
-                            if let Some(i) = kbdata.votes.iter().position(|x| *x == user.id) {
-                                kbdata.votes.swap_remove(i);
-                            } else {
-                                kbdata.votes.push(user.id);
-                            }
-                            tg.update_kb(
-                                original_message_id,
-                                make_report_kb(kbdata, original_message_id),
-                                TgChatId::Username(cfg.channel.clone()),
-                            );
+                            let active = toggle_vote(e.get_mut(), reaction, user.id);
+                            let at = time::get_time().sec;
+                            log_db(db.record_vote(&VoteRecord {
+                                post: original_message_id,
+                                user: user.id,
+                                reaction: reaction.to_owned(),
+                                at,
+                                active,
+                            }));
+                            log_db(db.record_user(&UserRecord {
+                                id: user.id,
+                                name: telegram::make_name(&user),
+                                seen_at: at,
+                            }));
+                            log_db(db.bump_counter("votes"));
+                            bs.kb_edits.entry(original_message_id).or_insert(now);
+                            bs.last_votes.insert(user.id, now);
+                            bs.state_rev += 1;
                             tg.answer_cbq(cbq_id, Some("ваш голос враховано".to_owned()));
                         },
                         Entry::Vacant(_) => {
-                            error!("no kbdata for this message_id");
-                            tg.answer_cbq(cbq_id, None);
+                            info!("no kbdata for this message_id, voting is probably closed");
+                            tg.answer_cbq(cbq_id, Some("голосування завершено".to_owned()));
                         },
                     }
                 }
@@ -135,6 +624,55 @@ fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config)
                 info!("ignore unknown CBQ");
             }
         },
+        TgUpdate {
+            message: None,
+            callback_query: Some(TgCallbackQuery {
+                id: cbq_id,
+                from: user,
+                message: None,
+                inline_message_id: Some(imi),
+                data: Some(d)
+            }),
+            inline_query: None,
+            ..
+        } => {
+            info!("CBQ: id: {} from: {} inline msg id: {} data: {}", cbq_id, user.id, imi, d);
+            if let Some((reaction, VoteTarget::Place(place_id))) = parse_cbq_data(&d, cfg) {
+                let pi = match get_info_for(st, &RfApi::new(), place_id) {
+                    Some(pi) => pi,
+                    None => {
+                        tg.answer_cbq(cbq_id, Some("спробуйте пізніше".to_owned()));
+                        return;
+                    },
+                };
+                if let Ok(mut guard) = st.write() {
+                    let bs = &mut *guard;
+                    let now = Instant::now();
+                    if votes_too_often(bs, user.id, now, cfg) {
+                        info!("user {} votes too often", user.id);
+                        tg.answer_cbq(cbq_id, Some("зачекайте трохи".to_owned()));
+                        return;
+                    }
+                    let ikb = bs.inline_kbdata.entry(imi.clone()).or_insert_with(|| InlineKbData {
+                        place_id,
+                        kbdata: KbData::new(pi.url, Some(pi.name), None),
+                    });
+                    toggle_vote(&mut ikb.kbdata, reaction, user.id);
+                    log_db(db.record_user(&UserRecord {
+                        id: user.id,
+                        name: telegram::make_name(&user),
+                        seen_at: time::get_time().sec,
+                    }));
+                    log_db(db.bump_counter("inline_votes"));
+                    bs.inline_kb_edits.entry(imi).or_insert(now);
+                    bs.last_votes.insert(user.id, now);
+                    bs.state_rev += 1;
+                    tg.answer_cbq(cbq_id, Some("ваш голос враховано".to_owned()));
+                }
+            } else {
+                info!("ignore unknown CBQ");
+            }
+        },
         TgUpdate {
             message: None,
             callback_query: None,
@@ -147,6 +685,7 @@ fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config)
             ..
         } => {
             let t0 = PreciseTime::now();
+            log_db(db.bump_counter("inline_queries"));
 
             let matching_ids: Vec<i32> = match st.read() {
                 Ok(guard) => {
@@ -178,6 +717,7 @@ fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config)
                 .map(|ci| ci.unwrap())
                 .map(|pi| {
                     let txt = fish::get_place_text(&pi);
+                    let kb = make_place_card_kb(&pi, cfg);
                     TgInlineQueryResult {
                         type_: "article".to_owned(),
                         id: format!("iqid_{}", pi.id),
@@ -191,10 +731,7 @@ fn process_update(st: &SafeBotState, upd: TgUpdate, updstr: &str, cfg: &Config)
                             parse_mode: "HTML".to_owned(),
                             disable_web_page_preview: false,
                         },
-                        reply_markup: Some(TgInlineKeyboardMarkup::url_button(
-                            "детальніше на вебсайті".to_owned(),
-                            pi.url,
-                        )),
+                        reply_markup: Some(kb),
                     }
                 })
                 .collect::<Vec<_>>();
@@ -236,7 +773,38 @@ type UserId = i32;
 #[derive(Serialize, Deserialize, Clone)]
 struct KbData {
     url: String,
-    votes: Vec<UserId>,
+    votes: BTreeMap<String, Vec<UserId>>, // by reaction key
+    published_at: i64,
+    #[serde(default)]
+    report_id: Option<i32>,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+impl KbData {
+    fn new(url: String, title: Option<String>, report_id: Option<i32>) -> Self {
+        KbData {
+            url,
+            votes: BTreeMap::new(),
+            published_at: time::get_time().sec,
+            report_id,
+            title,
+        }
+    }
+
+    fn count(&self, reaction: &str) -> usize {
+        self.votes.get(reaction).map_or(0, |v| v.len())
+    }
+
+    fn total(&self) -> usize {
+        self.votes.values().map(|v| v.len()).sum()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct InlineKbData {
+    place_id: i32,
+    kbdata: KbData,
 }
 
 #[derive(Default)]
@@ -246,6 +814,26 @@ struct BotState {
     cache: HashMap<i32, Option<RfPlaceInfo>>,
     top_ids: Vec<i32>,
     kbdata: HashMap<PostId, KbData>,
+    kb_edits: HashMap<PostId, Instant>, // posts with keyboards to update, since when
+    inline_kbdata: HashMap<String, InlineKbData>, // by inline_message_id
+    inline_kb_edits: HashMap<String, Instant>,
+    published: HashMap<i32, Published>, // by report id
+    publishing: HashSet<i32>,
+    queue: Vec<QueuedReport>, // ordered by publish_at
+    digest: Vec<i32>, // report ids
+    photo_ids: HashMap<String, String>, // photo url -> Telegram file_id
+    retries: Vec<RetryEntry>,
+    dead_letters: Vec<RetryEntry>,
+    retry_seq: u64,
+    last_polled_id: i32,
+    pinned: Option<PostId>, // featured report pinned by bot
+    articles: HashMap<i32, String>, // report id -> Telegraph page url
+    digest_at: i64,
+    last_votes: HashMap<UserId, Instant>,
+    state_rev: u64,
+    weekly_top_at: i64,
+    catalog_fetched_at: Option<i64>,
+    catalog_rev: u64, // bumped when catalog snapshot needs saving
 }
 
 impl Key for BotState {
@@ -265,13 +853,22 @@ fn modify_bot_state<F>(req: &mut Request, f: F)
 }
 
 fn load_state(req: &mut Request) -> IronResult<Response> {
-    let status = match req.get::<bodyparser::Struct<HashMap<PostId, KbData>>>() {
-        Ok(Some(kbdata)) => {
-            modify_bot_state(req, |bs: &mut BotState| {
-                bs.kbdata = kbdata;
-                info!("loaded state");
+    let status = match req.get::<bodyparser::Json>() {
+        Ok(Some(doc)) => {
+            let mut status = iron::status::InternalServerError;
+            modify_bot_state(req, |bs: &mut BotState| match persist::merge_state(doc, bs) {
+                Ok(doc) => {
+                    doc.apply_to(bs);
+                    bs.state_rev += 1;
+                    info!("loaded state");
+                    status = iron::status::Ok;
+                },
+                Err(err) => {
+                    error!("/load_state: {}", err);
+                    status = iron::status::BadRequest;
+                },
             });
-            iron::status::Ok
+            status
         },
         Ok(None) => {
             info!("/load_state request has empty body");
@@ -290,7 +887,7 @@ fn save_state(req: &mut Request) -> IronResult<Response> {
     if let Ok(arc_st) = req.get::<State<BotState>>() {
         if let Ok(guard) = arc_st.read() {
             let bs = &guard;
-            let resp = if let Ok(s) = serde_json::to_string(&bs.kbdata) {
+            let resp = if let Ok(s) = serde_json::to_string(&persist::StateDoc::from_state(bs)) {
                 Response::with((
                     iron::status::Ok,
                     iron::modifiers::Header(iron::headers::ContentType::json()),
@@ -306,98 +903,410 @@ fn save_state(req: &mut Request) -> IronResult<Response> {
     Ok(Response::with(iron::status::InternalServerError))
 }
 
+fn archive(_: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let resp = match persist::load_archive(&cfg.archive) {
+        Ok(archive) => if let Ok(s) = serde_json::to_string(&archive) {
+            Response::with((
+                iron::status::Ok,
+                iron::modifiers::Header(iron::headers::ContentType::json()),
+                s,
+            ))
+        } else {
+            Response::with(iron::status::InternalServerError)
+        },
+        Err(e) => {
+            info!("/archive: nothing archived yet ({})", e);
+            Response::with((
+                iron::status::Ok,
+                iron::modifiers::Header(iron::headers::ContentType::json()),
+                "{}",
+            ))
+        },
+    };
+
+    Ok(resp)
+}
+
 fn reload_places(req: &mut Request) -> IronResult<Response> {
     let rfapi = fish::RfApi::new();
-    let new_places = rfapi.fetch_all_places();
-    let new_fish = rfapi.fetch_all_fish();
+    let (new_places, new_fish) = match (rfapi.fetch_all_places(), rfapi.fetch_all_fish()) {
+        (Some(ps), Some(fs)) => (ps, fs),
+        _ => {
+            error!("/reload_places: keeping previous place list");
+            return Ok(Response::with(iron::status::InternalServerError));
+        },
+    };
 
     modify_bot_state(req, |bs: &mut BotState| {
         bs.places = new_places;
         bs.fishes = new_fish;
         bs.cache.clear();
+        bs.catalog_fetched_at = Some(time::get_time().sec);
         info!("reloaded place list and invalidated cache");
+        bs.catalog_rev += 1;
     });
 
     Ok(Response::with(iron::status::Ok))
 }
 
-#[derive(Deserialize, Clone)]
-struct TopIds {
-    ids: Vec<i32>,
+#[derive(Serialize)]
+struct CatalogStatus {
+    places: usize,
+    fishes: usize,
+    cached: usize,
+    fetched_at: Option<i64>,
+    age: Option<i64>,
 }
 
-fn set_top(req: &mut Request) -> IronResult<Response> {
-    let status = match req.get::<bodyparser::Struct<TopIds>>() {
-        Ok(Some(s)) => {
-            modify_bot_state(req, |bs: &mut BotState| {
-                bs.top_ids = s.ids.clone();
-                info!("updated top fishing places with {} items", bs.top_ids.len());
-            });
-            iron::status::Ok
-        },
-        Ok(None) => {
-            info!("/set_top request has empty body");
-            iron::status::BadRequest
-        },
-        Err(err) => {
-            error!("/set_top: {:?}", err);
-            iron::status::BadRequest
-        },
-    };
+fn status(req: &mut Request) -> IronResult<Response> {
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        if let Ok(guard) = arc_st.read() {
+            let bs = &guard;
+            let st = CatalogStatus {
+                places: bs.places.len(),
+                fishes: bs.fishes.len(),
+                cached: bs.cache.len(),
+                fetched_at: bs.catalog_fetched_at,
+                age: catalog_age(bs),
+            };
+            let resp = if let Ok(s) = serde_json::to_string(&st) {
+                Response::with((
+                    iron::status::Ok,
+                    iron::modifiers::Header(iron::headers::ContentType::json()),
+                    s,
+                ))
+            } else {
+                Response::with(iron::status::InternalServerError)
+            };
+            return Ok(resp);
+        }
+    }
 
-    Ok(Response::with(status))
+    Ok(Response::with(iron::status::InternalServerError))
 }
 
-#[derive(Deserialize, Clone)]
-struct Announcement {
-    chat: TgChatId,
-    text: String,
-    images: Option<Vec<String>>,
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url.query().and_then(|q| q.split('&')
+        .filter_map(|kv| {
+            let mut it = kv.splitn(2, '=');
+            match (it.next(), it.next()) {
+                (Some(k), Some(v)) if k == name => Some(v.to_owned()),
+                _ => None,
+            }
+        })
+        .next())
 }
 
-fn announce(req: &mut Request, cfg: &Config) -> IronResult<Response> {
-    let status = match req.get::<bodyparser::Struct<Announcement>>() {
-        Ok(Some(s)) => {
-            let tg = TgBotApi::new(&cfg.bottoken);
-            match tg.send_md_text(s.text, s.chat.clone(), None) {
-                Err(err) => {
-                    error!("/announce: {:?}", err);
-                    iron::status::InternalServerError
-                },
-                Ok(TgResponse {ok: false, description, ..}) => {
-                    error!("/announce: Bot API error: {:?}", description);
-                    iron::status::InternalServerError
-                },
-                Ok(_) => if let Some(is) = s.images {
-                    if !is.is_empty() {
-                        match tg.send_album(is.iter(), s.chat) {
-                            Err(err) => {
-                                error!("/announce: {:?}", err);
-                                iron::status::InternalServerError
-                            },
-                            Ok(TgResponse {ok: false, description, ..}) => {
-                                error!("/announce: Bot API error: {:?}", description);
-                                iron::status::InternalServerError
-                            },
-                            Ok(_) => {
-                                info!("/announce: message posted");
-                                iron::status::Ok
-                            },
-                        }
-                    } else {
-                        iron::status::Ok
-                    }
-                } else {
-                    iron::status::Ok
-                },
+fn date_param(req: &Request, name: &str) -> Result<Option<i64>, String> {
+    match query_param(req, name) {
+        Some(s) => time::strptime(&s, "%Y-%m-%d")
+            .map(|tm| Some(tm.to_timespec().sec))
+            .map_err(|e| format!("bad `{}` date {}: {}", name, s, e)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize)]
+struct History {
+    posts: Vec<PostRecord>,
+    votes: Vec<VoteRecord>,
+    counters: HashMap<String, i64>,
+}
+
+#[derive(Serialize)]
+struct PostStats {
+    message_id: PostId,
+    report_id: Option<i32>,
+    title: Option<String>,
+    url: String,
+    published_at: i64,
+    votes: BTreeMap<String, usize>,
+    total: usize,
+    archived: bool,
+}
+
+// Stats of posts published in [from, to), most voted first.
+fn collect_stats(kbdata: &HashMap<PostId, KbData>, archive: &HashMap<PostId, KbData>, from: i64, to: i64)
+-> Vec<PostStats> {
+    let mut stats = kbdata.iter().map(|e| (e, false))
+        .chain(archive.iter().map(|e| (e, true)))
+        .filter(|&((_, kb), _)| kb.published_at >= from && kb.published_at < to)
+        .map(|((id, kb), archived)| PostStats {
+            message_id: *id,
+            report_id: kb.report_id,
+            title: kb.title.clone(),
+            url: kb.url.clone(),
+            published_at: kb.published_at,
+            votes: kb.votes.iter().map(|(r, v)| (r.clone(), v.len())).collect(),
+            total: kb.total(),
+            archived,
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| b.total.cmp(&a.total).then(a.published_at.cmp(&b.published_at)));
+    stats
+}
+
+fn load_archive_if_any(cfg: &Config) -> HashMap<PostId, KbData> {
+    if !std::path::Path::new(&cfg.archive).exists() {
+        return HashMap::new();
+    }
+
+    persist::load_archive(&cfg.archive).unwrap_or_else(|e| {
+        error!("loading archive from {}: {}", cfg.archive, e);
+        HashMap::new()
+    })
+}
+
+fn vote_stats(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let range = date_param(req, "from").and_then(|from| date_param(req, "to").map(|to| (from, to)));
+    let (from, to) = match range {
+        Ok((from, to)) => (from.unwrap_or(0), to.unwrap_or(i64::max_value())),
+        Err(e) => {
+            error!("/vote_stats: {}", e);
+            return Ok(Response::with(iron::status::BadRequest));
+        },
+    };
+
+    let archive = load_archive_if_any(cfg);
+
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        if let Ok(guard) = arc_st.read() {
+            let stats = collect_stats(&guard.kbdata, &archive, from, to);
+            if let Ok(s) = serde_json::to_string(&stats) {
+                return Ok(Response::with((
+                    iron::status::Ok,
+                    iron::modifiers::Header(iron::headers::ContentType::json()),
+                    s,
+                )));
             }
+        }
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+fn make_weekly_top_text(stats: &[PostStats]) -> String {
+    let mut text = "<b>Найкращі звіти тижня</b>\n".to_owned();
+
+    for (i, ps) in stats.iter().enumerate() {
+        let counts = REACTIONS.iter()
+            .map(|&(key, emoji)| format!("{} {}", emoji, ps.votes.get(key).cloned().unwrap_or(0)))
+            .collect::<Vec<_>>();
+        text.push_str(&format!(
+            "\n{}. <a href=\"{}\">{}</a> {}",
+            i + 1,
+            ps.url,
+            ps.title.as_ref().map(|s| s.as_str()).unwrap_or("звіт"),
+            counts.join(" "),
+        ));
+    }
+
+    text
+}
+
+fn post_weekly_top(st: &SafeBotState, cfg: &Config) {
+    let now = time::get_time().sec;
+    let archive = load_archive_if_any(cfg);
+    let stats = match st.read() {
+        Ok(bs) => {
+            let mut stats = collect_stats(&bs.kbdata, &archive, now - 7 * 24 * 60 * 60, now);
+            stats.retain(|ps| ps.total > 0);
+            stats.truncate(cfg.weeklytopsize);
+            stats
+        },
+        Err(_) => return,
+    };
+
+    if stats.is_empty() {
+        info!("weekly top: no voted posts this week");
+        mark_weekly_top(st, now);
+        return;
+    }
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    match tg.send_rich_text(make_weekly_top_text(&stats), TgChatId::Username(cfg.channel.clone()), None, false) {
+        Ok(TgResponse {ok: true, ..}) => {
+            info!("weekly top: posted {} reports", stats.len());
+            mark_weekly_top(st, now);
+        },
+        Ok(TgResponse {description, ..}) => error!("weekly top: Bot API error: {:?}", description),
+        Err(err) => error!("weekly top: {:?}", err),
+    }
+}
+
+fn mark_weekly_top(st: &SafeBotState, now: i64) {
+    if let Ok(mut bs) = st.write() {
+        bs.weekly_top_at = now;
+        bs.state_rev += 1;
+    }
+}
+
+// Posts weekly top once, at `cfg.weeklytopday` (0 is Sunday) `cfg.weeklytophour` local time.
+fn spawn_weekly_top(st: SafeBotState, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(60));
+
+        let tm = time::now();
+        let due = tm.tm_wday == cfg.weeklytopday && tm.tm_hour == cfg.weeklytophour && st.read()
+            .map(|bs| time::get_time().sec - bs.weekly_top_at > 24 * 60 * 60)
+            .unwrap_or(false);
+        if due {
+            post_weekly_top(&st, cfg);
+        }
+    });
+}
+
+const MAX_TEXT_LEN: usize = 4096;
+
+fn make_digest_text(entries: &[String], period: i64) -> String {
+    let mut text = if period == 1 {
+        "<b>Нові звіти за день</b>\n".to_owned()
+    } else {
+        "<b>Нові звіти за тиждень</b>\n".to_owned()
+    };
+
+    for (i, e) in entries.iter().enumerate() {
+        let more = format!("\n\n...та ще {}", entries.len() - i);
+        if text.chars().count() + e.chars().count() + 2 + more.chars().count() > MAX_TEXT_LEN {
+            text.push_str(&more);
+            break;
+        }
+        text.push_str("\n");
+        text.push_str(e);
+    }
+
+    text
+}
+
+fn post_digest(st: &SafeBotState, db: &dyn Storage, cfg: &Config) {
+    let ids = match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+            bs.digest_at = time::get_time().sec;
+            bs.state_rev += 1;
+            bs.digest.clone()
+        },
+        Err(_) => return,
+    };
+
+    if ids.is_empty() {
+        info!("digest: no new reports");
+        return;
+    }
+
+    let fish = RfApi::new();
+    let reports = ids.iter().filter_map(|&id| {
+        let ri = fish.fetch_report_info(id);
+        if ri.is_none() {
+            error!("digest: unable to fetch report #{}", id);
+        }
+        ri
+    }).collect::<Vec<_>>();
+
+    let entries = match st.read() {
+        Ok(g) => reports.iter().map(|ri| fish::get_digest_entry(ri, &g.fishes)).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    let silent = cfg.silenthours.map_or(false, |w| in_hours(time::now().tm_hour, w));
+    match tg.send_rich_text(make_digest_text(&entries, cfg.digestperiod), TgChatId::Username(cfg.channel.clone()), None, silent) {
+        Ok(TgResponse {ok: true, ..}) => {
+            info!("digest: posted {} reports", entries.len());
+            log_db(db.bump_counter("digests"));
+            if let Ok(mut guard) = st.write() {
+                let bs = &mut *guard;
+                bs.digest.retain(|id| !ids.contains(id));
+                bs.state_rev += 1;
+            }
+        },
+        Ok(TgResponse {description, ..}) => error!("digest: Bot API error: {:?}", description),
+        Err(err) => error!("digest: {:?}", err),
+    }
+}
+
+// Posts digest at `cfg.digesthour` local time, every day or on `cfg.digestday` (0 is Sunday).
+fn spawn_digest(st: SafeBotState, db: SafeStorage, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(60));
+
+        let tm = time::now();
+        let due = (cfg.digestperiod == 1 || tm.tm_wday == cfg.digestday) && tm.tm_hour == cfg.digesthour && st.read()
+            .map(|bs| time::get_time().sec - bs.digest_at > 12 * 60 * 60)
+            .unwrap_or(false);
+        if due {
+            post_digest(&st, &**db, cfg);
+        }
+    });
+}
+
+// Remembers report for digest, returns true if it should not be published on its own.
+fn add_to_digest(st: &SafeBotState, report_id: i32, cfg: &Config) -> bool {
+    if cfg.digestperiod == 0 {
+        return false;
+    }
+
+    if let Ok(mut guard) = st.write() {
+        let bs = &mut *guard;
+        if !bs.digest.contains(&report_id) {
+            bs.digest.push(report_id);
+            bs.state_rev += 1;
+        }
+    }
+
+    cfg.digestonly
+}
+
+fn history(req: &mut Request) -> IronResult<Response> {
+    let range = date_param(req, "from").and_then(|from| date_param(req, "to").map(|to| (from, to)));
+    let (from, to) = match range {
+        Ok((from, to)) => (from.unwrap_or(0), to.unwrap_or(i64::max_value())),
+        Err(e) => {
+            error!("/history: {}", e);
+            return Ok(Response::with(iron::status::BadRequest));
+        },
+    };
+
+    if let Ok(db) = req.get::<Read<BotStorage>>() {
+        let h = db.posts_between(from, to).and_then(|posts|
+            db.votes_between(from, to).and_then(|votes|
+                db.counters().map(|counters| History { posts, votes, counters })
+            )
+        );
+        match h.and_then(|h| serde_json::to_string(&h).map_err(|e| e.to_string())) {
+            Ok(s) => return Ok(Response::with((
+                iron::status::Ok,
+                iron::modifiers::Header(iron::headers::ContentType::json()),
+                s,
+            ))),
+            Err(e) => error!("/history: {}", e),
+        }
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+#[derive(Deserialize, Clone)]
+struct TopIds {
+    ids: Vec<i32>,
+}
+
+fn set_top(req: &mut Request) -> IronResult<Response> {
+    let status = match req.get::<bodyparser::Struct<TopIds>>() {
+        Ok(Some(s)) => {
+            modify_bot_state(req, |bs: &mut BotState| {
+                bs.top_ids = s.ids.clone();
+                bs.state_rev += 1;
+                info!("updated top fishing places with {} items", bs.top_ids.len());
+            });
+            iron::status::Ok
         },
         Ok(None) => {
-            info!("/announce: request has empty body");
+            info!("/set_top request has empty body");
             iron::status::BadRequest
         },
         Err(err) => {
-            error!("/announce: {:?} while parsing request body", err);
+            error!("/set_top: {:?}", err);
             iron::status::BadRequest
         },
     };
@@ -406,94 +1315,541 @@ fn announce(req: &mut Request, cfg: &Config) -> IronResult<Response> {
 }
 
 #[derive(Deserialize, Clone)]
-struct PublishReport {
+struct Announcement {
+    chat: TgChatId,
+    text: String,
+    images: Option<Vec<String>>,
+}
+
+fn announce(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let resp = match req.get::<bodyparser::Struct<Announcement>>() {
+        Ok(Some(s)) => match req.get::<State<BotState>>() {
+            Ok(arc_st) => {
+                let tg = TgBotApi::new(&cfg.bottoken);
+                let images = s.images.unwrap_or_default();
+                match send_announcement(&tg, &s.chat, Some(&s.text), &images) {
+                    Ok(()) => {
+                        info!("/announce: message posted");
+                        Response::with(iron::status::Ok)
+                    },
+                    Err((text_sent, err)) => {
+                        error!("/announce: {}", err);
+                        let op = RetryOp::Announce {
+                            chat: s.chat,
+                            text: if text_sent { None } else { Some(s.text) },
+                            images,
+                        };
+                        let permanent = is_permanent_error(&err);
+                        let mut resp = json_response(&queue_retry(&arc_st, Failure { op, error: err, permanent }, cfg));
+                        // entry went to dead letters right away
+                        resp.status = Some(if permanent { iron::status::InternalServerError } else { iron::status::Accepted });
+                        resp
+                    },
+                }
+            },
+            Err(_) => Response::with(iron::status::InternalServerError),
+        },
+        Ok(None) => {
+            info!("/announce: request has empty body");
+            Response::with(iron::status::BadRequest)
+        },
+        Err(err) => {
+            error!("/announce: {:?} while parsing request body", err);
+            Response::with(iron::status::BadRequest)
+        },
+    };
+
+    Ok(resp)
+}
+
+// Hour windows wrap around midnight when `from` is greater than `to`.
+fn in_hours(hour: i32, (from, to): (i32, i32)) -> bool {
+    if from <= to {
+        hour >= from && hour < to
+    } else {
+        hour >= from || hour < to
+    }
+}
+
+fn parse_hours(s: &str) -> Option<(i32, i32)> {
+    let mut it = s.splitn(2, '-').map(|h| h.trim().parse::<i32>());
+    match (it.next(), it.next()) {
+        (Some(Ok(from)), Some(Ok(to))) if from >= 0 && from < 24 && to >= 0 && to < 24 => Some((from, to)),
+        _ => None,
+    }
+}
+
+fn json_response<S: serde::Serialize>(obj: &S) -> Response {
+    match serde_json::to_string(obj) {
+        Ok(s) => Response::with((
+            iron::status::Ok,
+            iron::modifiers::Header(iron::headers::ContentType::json()),
+            s,
+        )),
+        Err(_) => Response::with(iron::status::InternalServerError),
+    }
+}
+
+fn chat_id(chat: &str) -> TgChatId {
+    chat.parse().map(TgChatId::Integer).unwrap_or_else(|_| TgChatId::Username(chat.to_owned()))
+}
+
+fn api_result<T>(r: Result<TgResponse<T>, String>) -> Result<T, String> {
+    match r {
+        Err(err) => Err(err),
+        Ok(TgResponse {ok: false, error_code, description, ..}) => Err(format!("Bot API error {}: {:?}", error_code.unwrap_or(0), description)),
+        Ok(TgResponse {result: None, ..}) => Err("Bot API did not return result".to_owned()),
+        Ok(TgResponse {result: Some(r), ..}) => Ok(r),
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Layout {
+    Card,
+    CardAndAlbum,
+    Album, // photos with report text as caption
+}
+
+#[derive(Deserialize, Clone)]
+struct ReportRef {
     id: i32,
 }
 
-fn publish(req: &mut Request, cfg: &Config) -> IronResult<Response> {
-    let status = match req.get::<bodyparser::Struct<PublishReport>>() {
-        Ok(Some(p)) => {
-            let fish = RfApi::new();
-            if let Some(ri) = fish.fetch_report_info(p.id) {
-                if let Ok(arc_st) = req.get::<State<BotState>>() {
-                    let pi = ri.place.as_ref().and_then(|p| get_info_for(&arc_st, &fish, p.id));
-                    let tg = TgBotApi::new(&cfg.bottoken);
-                    let chat = TgChatId::Username(cfg.channel.clone());
-                    let kbdata = KbData {
-                        url: ri.url.clone(),
-                        votes: Vec::new(),
-                    };
-                    let resp = if let Ok(g) = arc_st.read() {
-                        tg.send_rich_text(
-                            fish::get_report_text(&ri, pi.as_ref(), &g.fishes),
-                            chat.clone(),
-                            None,
-                        )
-                    } else {
-                        Err(String::default())
-                    };
-                    match resp {
-                        Err(err) => {
-                            error!("/publish #{}: {:?}", ri.id, err);
-                            iron::status::InternalServerError
-                        },
-                        Ok(TgResponse {ok: false, description, ..}) => {
-                            error!("/publish #{}: Bot API error: {:?}", ri.id, description);
-                            iron::status::InternalServerError
-                        },
-                        Ok(TgResponse {ok: true, result: None, ..}) => {
-                            error!("/publish #{}: Bot API did not return msg", ri.id);
-                            iron::status::InternalServerError
-                        },
-                        Ok(TgResponse {ok: true, result: Some(TgMessageLite {message_id, ..}), ..}) => {
-                            tg.update_kb(
-                                message_id,
-                                make_report_kb(&kbdata, message_id),
-                                TgChatId::Username(cfg.channel.clone()),
-                            );
-                            if let Ok(mut guard) = arc_st.write() {
-                                let bs = &mut *guard;
-                                bs.kbdata.entry(message_id).or_insert(kbdata);
-                            }
-                            if ri.photos.len() < 2 || !cfg.publishalbums {
-                                info!("/publish #{}: message (no album) posted", ri.id);
-                                iron::status::Ok
-                            } else {
-                                match tg.send_album(ri.photos.iter().map(|p| &p.medium_url), chat) {
-                                    Err(err) => {
-                                        error!("/publish #{}: {:?}", ri.id, err);
-                                        iron::status::InternalServerError
-                                    },
-                                    Ok(TgResponse {ok: false, description, ..}) => {
-                                        error!("/publish #{}: Bot API error: {:?}", ri.id, description);
-                                        iron::status::InternalServerError
-                                    },
-                                    Ok(_) => {
-                                        info!("/publish #{}: message and album posted", ri.id);
-                                        iron::status::Ok
-                                    },
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    iron::status::InternalServerError // arc
+// Ok(false) if report was never published or its posts are already archived.
+fn update_report(st: &SafeBotState, report_id: i32, cfg: &Config) -> Result<bool, String> {
+    // every post of the report that is still live, with its vote keyboard
+    let live = match st.read() {
+        Ok(g) => match g.published.get(&report_id) {
+            Some(p) => p.sets().into_iter()
+                .map(|p| (p.clone(), p.card.and_then(|id| g.kbdata.get(&id).cloned())))
+                .filter(|(p, kbdata)| kbdata.is_some() || !p.routed.is_empty())
+                .collect::<Vec<_>>(),
+            None => return Ok(false),
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if live.is_empty() {
+        return Ok(false);
+    }
+
+    let fish = RfApi::new();
+    let ri = fish.fetch_report_info(report_id).ok_or_else(|| "unable to fetch report".to_owned())?;
+    let pi = ri.place.as_ref().and_then(|p| get_info_for(st, &fish, p.id));
+
+    let text = match st.read() {
+        Ok(g) => fish::get_report_text(&ri, pi.as_ref(), &g.fishes, g.articles.get(&report_id).map(|s| s.as_str())),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let caption = make_caption(&text, &ri.url);
+    if live.iter().any(|(p, _)| p.captioned) && caption.chars().count() > MAX_CAPTION_LEN {
+        return Err("updated report text does not fit into caption".to_owned());
+    }
+
+    // (chat, message id, new text, new keyboard), keyboard is None for captions
+    let mut edits = Vec::new();
+    for (published, kbdata) in live {
+        for p in &published.routed {
+            if published.captioned {
+                edits.push((p.chat.clone(), p.card, caption.clone(), None));
+            } else {
+                let kb = TgInlineKeyboardMarkup::url_button(LINK_TEXT.to_owned(), ri.url.clone());
+                edits.push((p.chat.clone(), p.card, text.clone(), Some(kb)));
+            }
+        }
+
+        if let (Some(card), Some(mut kbdata)) = (published.card, kbdata) {
+            kbdata.url = ri.url.clone();
+            kbdata.title = Some(ri.title.clone());
+            let kb = make_report_kb(&kbdata, card, cfg);
+            if published.captioned {
+                edits.push((cfg.channel.clone(), card, make_vote_text(&ri), Some(kb)));
+                if let Some(&first) = published.album.first() {
+                    edits.push((cfg.channel.clone(), first, caption.clone(), None));
                 }
             } else {
-                iron::status::InternalServerError // fetch report
+                edits.push((cfg.channel.clone(), card, text.clone(), Some(kb)));
             }
+
+            if let Ok(mut guard) = st.write() {
+                let bs = &mut *guard;
+                if let Some(kb) = bs.kbdata.get_mut(&card) {
+                    kb.url = kbdata.url;
+                    kb.title = kbdata.title;
+                    bs.state_rev += 1;
+                }
+            }
+        }
+    }
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    for (chat, msgid, text, kb) in edits {
+        let res = match kb {
+            Some(kb) => tg.edit_rich_text(msgid, text, chat_id(&chat), Some(kb)),
+            None => tg.edit_caption(msgid, text, chat_id(&chat)),
+        };
+        match api_result(res) {
+            // report text did not change, that's fine
+            Err(ref err) if err.contains("message is not modified") => (),
+            Err(err) => return Err(format!("{}: {}", chat, err)),
+            Ok(_) => info!("update_report #{}: message {} in {} updated", report_id, msgid, chat),
+        }
+    }
+
+    Ok(true)
+}
+
+fn update_report_handler(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let resp = match req.get::<bodyparser::Struct<ReportRef>>() {
+        Ok(Some(r)) => match req.get::<State<BotState>>() {
+            Ok(arc_st) => match update_report(&arc_st, r.id, cfg) {
+                Ok(true) => Response::with(iron::status::Ok),
+                Ok(false) => {
+                    info!("/update_report #{}: no live post for this report", r.id);
+                    Response::with(iron::status::NotFound)
+                },
+                Err(err) => {
+                    error!("/update_report #{}: {}", r.id, err);
+                    Response::with(iron::status::InternalServerError)
+                },
+            },
+            Err(_) => Response::with(iron::status::InternalServerError),
         },
         Ok(None) => {
-            info!("/publish: request has empty body");
-            iron::status::BadRequest
+            info!("/update_report: request has empty body");
+            Response::with(iron::status::BadRequest)
         },
         Err(err) => {
-            error!("/publish: {:?} while parsing request body", err);
-            iron::status::BadRequest
+            error!("/update_report: {:?} while parsing request body", err);
+            Response::with(iron::status::BadRequest)
         },
     };
 
-    Ok(Response::with(status))
+    Ok(resp)
+}
+
+// Ok(false) if report was never published.
+fn unpublish_report(st: &SafeBotState, db: &dyn Storage, report_id: i32, cfg: &Config) -> Result<bool, String> {
+    let published = match st.write() {
+        Ok(mut g) => match g.published.get(&report_id).cloned() {
+            Some(p) => p,
+            // not posted yet, only waiting for digest
+            None => {
+                let bs = &mut *g;
+                let pending = bs.digest.contains(&report_id);
+                bs.digest.retain(|&id| id != report_id);
+                if bs.articles.remove(&report_id).is_some() || pending {
+                    bs.state_rev += 1;
+                }
+                return Ok(pending);
+            },
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let tg = TgBotApi::new(&cfg.bottoken);
+    let messages = published.sets().iter().flat_map(|p| p.messages(cfg)).collect::<Vec<_>>();
+    for &(ref chat, msgid) in &messages {
+        match api_result(tg.delete_msg(msgid, chat_id(chat))) {
+            // deleted by hand already, or on previous attempt
+            Err(ref err) if err.contains("message to delete not found") => (),
+            Err(err) => return Err(format!("message {} in {}: {}", msgid, chat, err)),
+            Ok(_) => (),
+        }
+    }
+
+    if let Ok(mut guard) = st.write() {
+        let bs = &mut *guard;
+        bs.published.remove(&report_id);
+        bs.digest.retain(|&id| id != report_id);
+        bs.articles.remove(&report_id);
+        for card in published.sets().iter().filter_map(|p| p.card) {
+            bs.kbdata.remove(&card);
+            bs.kb_edits.remove(&card);
+            // deleted message is unpinned by Telegram
+            if bs.pinned == Some(card) {
+                bs.pinned = None;
+            }
+        }
+        bs.state_rev += 1;
+    }
+
+    log_db(db.bump_counter("unpublished"));
+    info!("unpublish #{}: {} message(s) deleted", report_id, messages.len());
+    Ok(true)
+}
+
+fn unpublish(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let resp = match req.get::<bodyparser::Struct<ReportRef>>() {
+        Ok(Some(r)) => match (req.get::<State<BotState>>(), req.get::<Read<BotStorage>>()) {
+            (Ok(arc_st), Ok(db)) => match unpublish_report(&arc_st, &**db, r.id, cfg) {
+                Ok(true) => Response::with(iron::status::Ok),
+                Ok(false) => {
+                    info!("/unpublish #{}: report was not published", r.id);
+                    Response::with(iron::status::NotFound)
+                },
+                Err(err) => {
+                    error!("/unpublish #{}: {}", r.id, err);
+                    Response::with(iron::status::InternalServerError)
+                },
+            },
+            _ => Response::with(iron::status::InternalServerError),
+        },
+        Ok(None) => {
+            info!("/unpublish: request has empty body");
+            Response::with(iron::status::BadRequest)
+        },
+        Err(err) => {
+            error!("/unpublish: {:?} while parsing request body", err);
+            Response::with(iron::status::BadRequest)
+        },
+    };
+
+    Ok(resp)
+}
+
+#[derive(Serialize)]
+struct RouteInfo {
+    report_id: i32,
+    chats: Vec<String>,
+}
+
+// Shows where `/publish` would post given report, without posting it.
+fn route_dry_run(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let id = match query_param(req, "id").and_then(|s| s.parse::<i32>().ok()) {
+        Some(id) => id,
+        None => {
+            info!("/route: no report id given");
+            return Ok(Response::with(iron::status::BadRequest));
+        },
+    };
+
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        let fish = RfApi::new();
+        match fish.fetch_report_info(id) {
+            Some(ri) => {
+                let pi = ri.place.as_ref().and_then(|p| get_info_for(&arc_st, &fish, p.id));
+                return Ok(json_response(&RouteInfo {
+                    report_id: id,
+                    chats: routing::route_report(&cfg.routes, &ri, pi.as_ref(), &cfg.channel),
+                }));
+            },
+            None => error!("/route #{}: unable to fetch report", id),
+        }
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+#[derive(Serialize)]
+struct CardPreview {
+    text: String,
+    keyboard: TgInlineKeyboardMarkup,
+    sent: Option<Vec<PostId>>, // messages in test chat
+}
+
+fn path_id(req: &Request) -> Option<i32> {
+    req.extensions.get::<router::Router>()
+        .and_then(|params| params.find("id"))
+        .and_then(|s| s.parse().ok())
+}
+
+// With `?send=yes` preview also goes to test chat.
+fn preview_target(req: &Request, cfg: &Config) -> Result<Option<String>, String> {
+    match query_param(req, "send").as_ref().map(|s| s.as_str()) {
+        Some("yes") if cfg.testchat.is_empty() => Err("test chat is not configured".to_owned()),
+        Some("yes") => Ok(Some(cfg.testchat.clone())),
+        _ => Ok(None),
+    }
+}
+
+fn preview_place(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let (id, target) = match (path_id(req), preview_target(req, cfg)) {
+        (Some(id), Ok(target)) => (id, target),
+        (_, Err(e)) => {
+            info!("/preview/place: {}", e);
+            return Ok(Response::with(iron::status::BadRequest));
+        },
+        (None, _) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        let pi = match get_info_for(&arc_st, &RfApi::new(), id) {
+            Some(pi) => pi,
+            None => return Ok(Response::with(iron::status::NotFound)),
+        };
+        let mut preview = CardPreview {
+            text: fish::get_place_text(&pi),
+            keyboard: make_place_card_kb(&pi, cfg),
+            sent: None,
+        };
+
+        if let Some(chat) = target {
+            // vote buttons would count votes in test chat, so it gets a plain link
+            let kb = TgInlineKeyboardMarkup::url_button("детальніше на вебсайті".to_owned(), pi.url.clone());
+            let tg = TgBotApi::new(&cfg.bottoken);
+            match api_result(tg.send_rich_text(preview.text.clone(), chat_id(&chat), Some(kb), false)) {
+                Ok(msg) => preview.sent = Some(vec![msg.message_id]),
+                Err(err) => {
+                    error!("/preview/place/{}: {}", id, err);
+                    return Ok(Response::with(iron::status::InternalServerError));
+                },
+            }
+        }
+
+        return Ok(json_response(&preview));
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+fn preview_report(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let (id, target) = match (path_id(req), preview_target(req, cfg)) {
+        (Some(id), Ok(target)) => (id, target),
+        (_, Err(e)) => {
+            info!("/preview/report: {}", e);
+            return Ok(Response::with(iron::status::BadRequest));
+        },
+        (None, _) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        let fish = RfApi::new();
+        let ri = match fish.fetch_report_info(id) {
+            Some(ri) => ri,
+            None => return Ok(Response::with(iron::status::NotFound)),
+        };
+        let pi = ri.place.as_ref().and_then(|p| get_info_for(&arc_st, &fish, p.id));
+        // article is not created for preview, only existing one is linked
+        let text = match arc_st.read() {
+            Ok(g) => fish::get_report_text(&ri, pi.as_ref(), &g.fishes, g.articles.get(&id).map(|s| s.as_str())),
+            Err(_) => return Ok(Response::with(iron::status::InternalServerError)),
+        };
+        // message id is not known before posting
+        let kbdata = KbData::new(ri.url.clone(), Some(ri.title.clone()), Some(ri.id));
+        let mut preview = CardPreview {
+            keyboard: make_report_kb(&kbdata, 0, cfg),
+            text,
+            sent: None,
+        };
+
+        if let Some(chat) = target {
+            // posted like in routed chats, with a link instead of vote buttons
+            let tg = TgBotApi::new(&cfg.bottoken);
+            let caption = make_caption(&preview.text, &ri.url);
+            let sent = if fits_caption(&ri, &caption, cfg) {
+                let card = ChatCard { chat: &chat, ri: &ri, text: caption, home: false, silent: false };
+                send_captioned(&arc_st, &tg, &card, ChatSent::default())
+            } else {
+                let card = ChatCard { chat: &chat, ri: &ri, text: preview.text.clone(), home: false, silent: false };
+                send_card(&arc_st, &tg, &card, ChatSent::default(), cfg)
+            };
+            if let Some(err) = sent.error {
+                error!("/preview/report/{}: {}", id, err);
+                return Ok(Response::with(iron::status::InternalServerError));
+            }
+            preview.sent = Some(sent.card.into_iter().chain(sent.album).collect());
+        }
+
+        return Ok(json_response(&preview));
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+// Date until which unsigned vote buttons are accepted, `none` to reject them right away.
+fn parse_unsigned_until(s: &str) -> Option<i64> {
+    if s == "none" {
+        return Some(0);
+    }
+    time::strptime(s, "%Y-%m-%d").ok().map(|tm| tm.to_timespec().sec)
+}
+
+fn load_routes() -> Vec<Route> {
+    match std::env::var("RVFISH_ROUTES") {
+        Ok(path) => persist::load_json(&path).unwrap_or_else(|e| {
+            error!("loading routes from {}: {}", path, e);
+            std::process::exit(1);
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn published(req: &mut Request) -> IronResult<Response> {
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        if let Ok(guard) = arc_st.read() {
+            return Ok(json_response(&guard.published));
+        }
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    stopping: AtomicBool,
+}
+
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    // Counts work in while the guard lives, None once shutdown started.
+    fn enter(&self) -> Option<InFlightGuard<'_>> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard(&self.count);
+        if self.stopping.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(guard)
+        }
+    }
+}
+
+// Counts requests being handled and turns new ones away once shutdown started.
+// Background workers that post to Telegram are counted the same way.
+struct Gate<H> {
+    handler: H,
+    inflight: Arc<InFlight>,
+}
+
+impl<H: Handler> Handler for Gate<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match self.inflight.enter() {
+            Some(_guard) => self.handler.handle(req),
+            None => Ok(Response::with(iron::status::ServiceUnavailable)),
+        }
+    }
+}
+
+fn shutdown(st: &SafeBotState, inflight: &InFlight, cfg: &Config) {
+    inflight.stopping.store(true, Ordering::SeqCst);
+
+    let deadline = Instant::now() + Duration::from_secs(cfg.shutdowntimeout);
+    while inflight.count.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let left = inflight.count.load(Ordering::SeqCst);
+    if left > 0 {
+        warn!("shutdown: {} requests or jobs still in flight after {}s", left, cfg.shutdowntimeout);
+    }
+
+    flush_kb_edits(st, cfg, Duration::from_millis(0));
+
+    if let Ok(guard) = st.read() {
+        match persist::save_json(&cfg.statefile, &persist::StateDoc::from_state(&guard)) {
+            Ok(_) => info!("shutdown: state saved to {}", cfg.statefile),
+            Err(e) => error!("shutdown: saving state to {}: {}", cfg.statefile, e),
+        }
+    }
 }
 
 struct Config {
@@ -502,7 +1858,41 @@ struct Config {
     channel: String,
     listenpath: String,
     listenaddr: String,
-    publishalbums: bool,
+    layout: Layout,
+    snapshot: String,
+    statefile: String,
+    savedelay: u64,
+    database: String,
+    retention: i64,
+    archive: String,
+    shutdowntimeout: u64,
+    cbqsecret: String,
+    cbqunsigneduntil: Option<i64>, // None if not configured, same as `none`
+    kbeditdelay: u64,
+    votecooldown: u64,
+    weeklytop: bool,
+    weeklytopday: i32,
+    weeklytophour: i32,
+    weeklytopsize: usize,
+    inlinevotes: bool,
+    votesyncurl: String,
+    votesynctoken: String,
+    votesyncperiod: u64,
+    quiethours: Option<(i32, i32)>,
+    silenthours: Option<(i32, i32)>,
+    digestperiod: i64, // days, 0 if off
+    digestonly: bool,
+    digestday: i32,
+    digesthour: i32,
+    routes: Vec<Route>,
+    retrydelay: i64,
+    retryattempts: u32,
+    pollperiod: u64, // seconds, 0 if off
+    testchat: String,
+    pinfeatured: bool,
+    telegraphurl: String,
+    telegraphtoken: String,
+    telegraphminlen: usize,
 }
 
 lazy_static! {
@@ -512,7 +1902,56 @@ lazy_static! {
         channel: std::env::var("RVFISH_CHANNEL").unwrap_or_default(),
         listenpath: std::env::var("RVFISH_LISTENPATH").unwrap_or_else(|_| "/bot".to_owned()),
         listenaddr: std::env::var("RVFISH_LISTENADDR").unwrap_or_else(|_| "localhost:2358".to_owned()),
-        publishalbums: std::env::var("RVFISH_PUBLISHALBUMS").map(|s| s == "yes").unwrap_or_default(),
+        layout: match std::env::var("RVFISH_LAYOUT").as_ref().map(|s| s.as_str()) {
+            Ok("album") => Layout::Album,
+            Ok("card+album") => Layout::CardAndAlbum,
+            Ok(_) => Layout::Card,
+            // setting used before layouts were introduced
+            Err(_) => match std::env::var("RVFISH_PUBLISHALBUMS").as_ref().map(|s| s.as_str()) {
+                Ok("yes") => Layout::CardAndAlbum,
+                _ => Layout::Card,
+            },
+        },
+        snapshot: std::env::var("RVFISH_SNAPSHOT").unwrap_or_else(|_| "rvfish_catalog.json".to_owned()),
+        statefile: std::env::var("RVFISH_STATEFILE").unwrap_or_else(|_| "rvfish_state.json".to_owned()),
+        savedelay: std::env::var("RVFISH_SAVEDELAY").ok().and_then(|s| s.parse().ok()).unwrap_or(5),
+        database: std::env::var("RVFISH_DATABASE").unwrap_or_else(|_| "rvfish.sqlite".to_owned()),
+        retention: std::env::var("RVFISH_RETENTIONDAYS").ok().and_then(|s| s.parse().ok()).unwrap_or(0),
+        archive: std::env::var("RVFISH_ARCHIVE").unwrap_or_else(|_| "rvfish_archive.json".to_owned()),
+        shutdowntimeout: std::env::var("RVFISH_SHUTDOWNTIMEOUT").ok().and_then(|s| s.parse().ok()).unwrap_or(10),
+        cbqsecret: std::env::var("RVFISH_CBQSECRET")
+            .or_else(|_| std::env::var("RVFISH_BOTTOKEN"))
+            .unwrap_or_default(),
+        cbqunsigneduntil: std::env::var("RVFISH_CBQUNSIGNEDUNTIL").ok().and_then(|s| parse_unsigned_until(&s)),
+        kbeditdelay: std::env::var("RVFISH_KBEDITDELAY").ok().and_then(|s| s.parse().ok()).unwrap_or(2000),
+        votecooldown: std::env::var("RVFISH_VOTECOOLDOWN").ok().and_then(|s| s.parse().ok()).unwrap_or(1000),
+        weeklytop: std::env::var("RVFISH_WEEKLYTOP").map(|s| s == "yes").unwrap_or_default(),
+        weeklytopday: std::env::var("RVFISH_WEEKLYTOPDAY").ok().and_then(|s| s.parse().ok()).unwrap_or(0),
+        weeklytophour: std::env::var("RVFISH_WEEKLYTOPHOUR").ok().and_then(|s| s.parse().ok()).unwrap_or(18),
+        weeklytopsize: std::env::var("RVFISH_WEEKLYTOPSIZE").ok().and_then(|s| s.parse().ok()).unwrap_or(5),
+        inlinevotes: std::env::var("RVFISH_INLINEVOTES").map(|s| s == "yes").unwrap_or_default(),
+        votesyncurl: std::env::var("RVFISH_VOTESYNCURL").unwrap_or_default(),
+        votesynctoken: std::env::var("RVFISH_VOTESYNCTOKEN").unwrap_or_default(),
+        votesyncperiod: std::env::var("RVFISH_VOTESYNCPERIOD").ok().and_then(|s| s.parse().ok()).unwrap_or(600),
+        quiethours: std::env::var("RVFISH_QUIETHOURS").ok().and_then(|s| parse_hours(&s)),
+        silenthours: std::env::var("RVFISH_SILENTHOURS").ok().and_then(|s| parse_hours(&s)),
+        digestperiod: match std::env::var("RVFISH_DIGEST").as_ref().map(|s| s.as_str()) {
+            Ok("daily") => 1,
+            Ok("weekly") => 7,
+            _ => 0,
+        },
+        digestonly: std::env::var("RVFISH_DIGESTONLY").map(|s| s == "yes").unwrap_or_default(),
+        digestday: std::env::var("RVFISH_DIGESTDAY").ok().and_then(|s| s.parse().ok()).unwrap_or(0),
+        digesthour: std::env::var("RVFISH_DIGESTHOUR").ok().and_then(|s| s.parse().ok()).unwrap_or(20),
+        routes: load_routes(),
+        retrydelay: std::env::var("RVFISH_RETRYDELAY").ok().and_then(|s| s.parse().ok()).unwrap_or(60),
+        retryattempts: std::env::var("RVFISH_RETRYATTEMPTS").ok().and_then(|s| s.parse().ok()).unwrap_or(5),
+        pollperiod: std::env::var("RVFISH_POLLPERIOD").ok().and_then(|s| s.parse().ok()).unwrap_or(0),
+        testchat: std::env::var("RVFISH_TESTCHAT").unwrap_or_default(),
+        pinfeatured: std::env::var("RVFISH_PINFEATURED").map(|s| s == "yes").unwrap_or_default(),
+        telegraphurl: std::env::var("RVFISH_TELEGRAPHURL").unwrap_or_else(|_| "https://api.telegra.ph".to_owned()),
+        telegraphtoken: std::env::var("RVFISH_TELEGRAPHTOKEN").unwrap_or_default(),
+        telegraphminlen: std::env::var("RVFISH_TELEGRAPHMINLEN").ok().and_then(|s| s.parse().ok()).unwrap_or(1000),
     };
 }
 
@@ -535,10 +1974,15 @@ fn main() {
 
     log_builder.init();
 
+    // buttons of posts published before signing stop working
+    if CONFIG.cbqunsigneduntil.is_none() {
+        warn!("RVFISH_CBQUNSIGNEDUNTIL is not set to a date (YYYY-MM-DD) or `none`, unsigned vote buttons are not accepted");
+    }
+
     fn bot(req: &mut Request, cfg: &Config) -> IronResult<Response> {
         match telegram::read_update(&mut req.body) {
-            Ok((upd, updstr)) => if let Ok(arc_st) = req.get::<State<BotState>>() {
-                process_update(&arc_st, upd, &updstr, cfg);
+            Ok((upd, updstr)) => if let (Ok(arc_st), Ok(db)) = (req.get::<State<BotState>>(), req.get::<Read<BotStorage>>()) {
+                process_update(&arc_st, &**db, upd, &updstr, cfg);
             },
             Err(err) => error!("read_update error: {}", err),
         }
@@ -548,7 +1992,14 @@ fn main() {
 
     let bot_handler = |req: &mut Request| bot(req, &CONFIG);
     let announce_handler = |req: &mut Request| announce(req, &CONFIG);
-    let publish_handler = |req: &mut Request| publish(req, &CONFIG);
+    let publish_handler = |req: &mut Request| publish::publish(req, &CONFIG);
+    let update_handler = |req: &mut Request| update_report_handler(req, &CONFIG);
+    let unpublish_handler = |req: &mut Request| unpublish(req, &CONFIG);
+    let route_handler = |req: &mut Request| route_dry_run(req, &CONFIG);
+    let preview_place_handler = |req: &mut Request| preview_place(req, &CONFIG);
+    let preview_report_handler = |req: &mut Request| preview_report(req, &CONFIG);
+    let archive_handler = |req: &mut Request| archive(req, &CONFIG);
+    let vote_stats_handler = |req: &mut Request| vote_stats(req, &CONFIG);
 
     let mut router = router::Router::new();
     router.post(&CONFIG.listenpath, bot_handler, "bot");
@@ -556,17 +2007,165 @@ fn main() {
     router.post("/set_top", set_top, "set_top");
     router.post("/announce", announce_handler, "announce");
     router.post("/publish", publish_handler, "publish");
+    router.post("/update_report", update_handler, "update_report");
+    router.post("/unpublish", unpublish_handler, "unpublish");
     router.post("/load_state", load_state, "load_state");
     router.get("/save_state", save_state, "save_state");
+    router.get("/status", status, "status");
+    router.get("/history", history, "history");
+    router.get("/archive", archive_handler, "archive");
+    router.get("/vote_stats", vote_stats_handler, "vote_stats");
+    router.get("/published", published, "published");
+    router.get("/route", route_handler, "route");
+    router.get("/preview/place/:id", preview_place_handler, "preview_place");
+    router.get("/preview/report/:id", preview_report_handler, "preview_report");
+    router.get("/retries", publish::retries, "retries");
+    router.post("/retries/retry", |req: &mut Request| publish::change_retry(req, "/retries/retry", publish::retry_now), "retry");
+    router.post("/retries/discard", |req: &mut Request| publish::change_retry(req, "/retries/discard", publish::discard_retry), "discard");
 
-    let botstate = BotState::default();
+    let mut botstate = BotState::default();
+    load_catalog(&mut botstate, &CONFIG.snapshot);
+    load_state_file(&mut botstate, &CONFIG.statefile);
+
+    let db: SafeStorage = match storage::open(&CONFIG.database) {
+        Ok(db) => Arc::new(db),
+        Err(e) => {
+            error!("opening storage {}: {}", CONFIG.database, e);
+            return;
+        },
+    };
+
+    let botstate = Arc::new(RwLock::new(botstate));
+    let inflight = Arc::new(InFlight::default());
+    spawn_state_saver(botstate.clone(), CONFIG.statefile.clone(), CONFIG.savedelay);
+    spawn_catalog_saver(botstate.clone(), &CONFIG);
+    spawn_kb_flusher(botstate.clone(), &CONFIG);
+    if CONFIG.weeklytop {
+        spawn_weekly_top(botstate.clone(), &CONFIG);
+    }
+    if !CONFIG.votesyncurl.is_empty() {
+        spawn_vote_sync(botstate.clone(), &CONFIG);
+    }
+    if CONFIG.retention > 0 {
+        spawn_archiver(botstate.clone(), &CONFIG);
+    }
+    publish::spawn_publish_queue(botstate.clone(), db.clone(), inflight.clone(), &CONFIG);
+    publish::spawn_retrier(botstate.clone(), db.clone(), inflight.clone(), &CONFIG);
+    if CONFIG.pollperiod > 0 {
+        publish::spawn_poller(botstate.clone(), db.clone(), inflight.clone(), &CONFIG);
+    }
+    if CONFIG.digestperiod > 0 {
+        spawn_digest(botstate.clone(), db.clone(), &CONFIG);
+    }
 
     let mut chain = Chain::new(router);
-    chain.link(State::<BotState>::both(botstate));
+    chain.link(State::<BotState>::both(botstate.clone()));
+    chain.link_before(Read::<BotStorage>::one(db));
     chain.link_before(Read::<bodyparser::MaxBodyLength>::one(1024 * 1024));
 
-    match Iron::new(chain).http(&CONFIG.listenaddr) {
-        Ok(_) => {}
+    let gate = Gate {
+        handler: chain,
+        inflight: inflight.clone(),
+    };
+
+    let (stop_tx, stop_rx) = mpsc::channel();
+    if let Err(e) = ctrlc::set_handler(move || { let _ = stop_tx.send(()); }) {
+        error!("unable to set signal handler: {}", e);
+    }
+
+    match Iron::new(gate).http(&CONFIG.listenaddr) {
+        Ok(listening) => {
+            if stop_rx.recv().is_ok() {
+                info!("received termination signal, shutting down");
+                shutdown(&botstate, &inflight, &CONFIG);
+                std::process::exit(0);
+            }
+            // no signal handler, serve until killed
+            drop(listening);
+        }
         Err(e) => error!("iron http failure {}", e.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn hours_wrap_around_midnight() {
+        assert_eq!(parse_hours("23-7"), Some((23, 7)));
+        assert_eq!(parse_hours(" 9 - 18 "), Some((9, 18)));
+        assert_eq!(parse_hours("24-7"), None);
+        assert_eq!(parse_hours("9"), None);
+        assert_eq!(parse_hours("a-b"), None);
+
+        assert!(in_hours(23, (23, 7)));
+        assert!(in_hours(3, (23, 7)));
+        assert!(!in_hours(7, (23, 7)));
+        assert!(!in_hours(12, (23, 7)));
+        assert!(in_hours(9, (9, 18)));
+        assert!(!in_hours(18, (9, 18)));
+        assert!(!in_hours(5, (5, 5)));
+    }
+
+    #[test]
+    fn cbq_data_round_trip() {
+        let cfg = testutil::config();
+        let d = make_cbq_data("go", VoteTarget::Post(42), &cfg);
+        assert!(d.starts_with("v1:go:42:"));
+        match parse_cbq_data(&d, &cfg) {
+            Some(("go", VoteTarget::Post(42))) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let d = make_cbq_data("like", VoteTarget::Place(7), &cfg);
+        match parse_cbq_data(&d, &cfg) {
+            Some(("like", VoteTarget::Place(7))) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn cbq_data_rejected() {
+        let cfg = testutil::config();
+        let d = make_cbq_data("go", VoteTarget::Post(42), &cfg);
+        let forged = d.replace("v1:go:42:", "v1:go:43:");
+        assert!(parse_cbq_data(&forged, &cfg).is_none());
+        assert!(parse_cbq_data(&d.replace("v1:go:", "v1:boo:"), &cfg).is_none());
+
+        assert!(parse_cbq_data("v1:", &cfg).is_none());
+        assert!(parse_cbq_data("v1:x", &cfg).is_none());
+        assert!(parse_cbq_data("v1:like:5", &cfg).is_none());
+
+        // unsigned window is closed when not configured
+        assert!(parse_cbq_data("u5", &cfg).is_none());
+        assert!(parse_cbq_data("like:5", &cfg).is_none());
+    }
+
+    #[test]
+    fn cbq_data_unsigned_while_window_open() {
+        let mut cfg = testutil::config();
+        cfg.cbqunsigneduntil = Some(time::get_time().sec + 3600);
+        match parse_cbq_data("u5", &cfg) {
+            Some(("like", VoteTarget::Post(5))) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+        match parse_cbq_data("go:5", &cfg) {
+            Some(("go", VoteTarget::Post(5))) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+        // place votes were always signed
+        assert!(parse_cbq_data("like:p5", &cfg).is_none());
+
+        cfg.cbqunsigneduntil = Some(time::get_time().sec - 1);
+        assert!(parse_cbq_data("u5", &cfg).is_none());
+    }
+
+    #[test]
+    fn unsigned_until() {
+        assert_eq!(parse_unsigned_until("none"), Some(0));
+        assert!(parse_unsigned_until("2019-09-01").unwrap() > 0);
+        assert_eq!(parse_unsigned_until("soon"), None);
+    }
+}
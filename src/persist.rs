@@ -0,0 +1,279 @@
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time;
+
+use fish::{RfPlace, RfFish, RfPlaceInfo};
+use publish::{Published, QueuedReport, RetryEntry};
+use {BotState, InlineKbData, KbData, PostId};
+
+static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// Write to a temporary file next to the target and rename it over,
+// so a crash never leaves a half-written file behind.
+pub fn save_json<S: Serialize>(path: &str, obj: &S) -> Result<(), String> {
+    let tmp = format!("{}.{}.tmp", path, TMP_SEQ.fetch_add(1, Ordering::SeqCst));
+    let bod = serde_json::to_vec(obj).map_err(|e| e.to_string())?;
+
+    fs::File::create(&tmp)
+        .and_then(|mut f| f.write_all(&bod).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| e.to_string())
+}
+
+pub fn load_json<D: DeserializeOwned>(path: &str) -> Result<D, String> {
+    fs::File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogSnapshot {
+    pub fetched_at: i64,
+    pub places: Vec<RfPlace>,
+    pub fishes: Vec<RfFish>,
+    pub cache: HashMap<i32, RfPlaceInfo>,
+}
+
+pub const STATE_VERSION: u64 = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct StateDoc {
+    pub version: u64,
+    pub kbdata: HashMap<PostId, KbData>,
+    #[serde(default)]
+    pub top_ids: Vec<i32>,
+    #[serde(default)]
+    pub weekly_top_at: i64,
+    #[serde(default)]
+    pub inline_kbdata: HashMap<String, InlineKbData>,
+    #[serde(default)]
+    pub published: HashMap<i32, Published>,
+    #[serde(default)]
+    pub queue: Vec<QueuedReport>,
+    #[serde(default)]
+    pub digest: Vec<i32>,
+    #[serde(default)]
+    pub digest_at: i64,
+    #[serde(default)]
+    pub photo_ids: HashMap<String, String>,
+    #[serde(default)]
+    pub retries: Vec<RetryEntry>,
+    #[serde(default)]
+    pub dead_letters: Vec<RetryEntry>,
+    #[serde(default)]
+    pub retry_seq: u64,
+    #[serde(default)]
+    pub last_polled_id: i32,
+    #[serde(default)]
+    pub pinned: Option<PostId>,
+    #[serde(default)]
+    pub articles: HashMap<i32, String>,
+}
+
+impl StateDoc {
+    pub fn from_state(bs: &BotState) -> Self {
+        StateDoc {
+            version: STATE_VERSION,
+            kbdata: bs.kbdata.clone(),
+            top_ids: bs.top_ids.clone(),
+            weekly_top_at: bs.weekly_top_at,
+            inline_kbdata: bs.inline_kbdata.clone(),
+            published: bs.published.clone(),
+            queue: bs.queue.clone(),
+            digest: bs.digest.clone(),
+            digest_at: bs.digest_at,
+            photo_ids: bs.photo_ids.clone(),
+            retries: bs.retries.clone(),
+            dead_letters: bs.dead_letters.clone(),
+            retry_seq: bs.retry_seq,
+            last_polled_id: bs.last_polled_id,
+            pinned: bs.pinned,
+            articles: bs.articles.clone(),
+        }
+    }
+
+    pub fn apply_to(self, bs: &mut BotState) {
+        bs.kbdata = self.kbdata;
+        bs.top_ids = self.top_ids;
+        bs.weekly_top_at = self.weekly_top_at;
+        bs.inline_kbdata = self.inline_kbdata;
+        bs.published = self.published;
+        bs.digest = self.digest;
+        bs.digest_at = self.digest_at;
+        bs.photo_ids = self.photo_ids;
+        bs.retries = self.retries;
+        bs.dead_letters = self.dead_letters;
+        bs.retry_seq = self.retry_seq;
+        bs.last_polled_id = self.last_polled_id;
+        bs.pinned = self.pinned;
+        bs.articles = self.articles;
+        bs.queue = self.queue;
+        bs.queue.sort_by_key(|q| (q.publish_at, q.queued_at));
+    }
+}
+
+// Brings any state document ever produced by `/save_state` up to
+// STATE_VERSION. Documents without `version` are the legacy bare
+// `{post_id: kbdata}` maps and are treated as version 0.
+pub fn upgrade_state(doc: Value) -> Result<StateDoc, String> {
+    upgrade_doc(doc).and_then(|doc| serde_json::from_value(doc).map_err(|e| e.to_string()))
+}
+
+// Upgrades uploaded document and takes fields it lacks from `bs`, so loading
+// e.g. a legacy kbdata map leaves the rest of the state alone.
+pub fn merge_state(doc: Value, bs: &BotState) -> Result<StateDoc, String> {
+    let doc = upgrade_doc(doc)?;
+    let mut merged = serde_json::to_value(StateDoc::from_state(bs)).map_err(|e| e.to_string())?;
+
+    if let (Value::Object(ref mut m), Value::Object(d)) = (&mut merged, doc) {
+        m.extend(d);
+    }
+
+    serde_json::from_value(merged).map_err(|e| e.to_string())
+}
+
+fn upgrade_doc(mut doc: Value) -> Result<Value, String> {
+    let mut version = match doc.get("version") {
+        Some(v) => v.as_u64().ok_or_else(|| format!("bad state version: {}", v))?,
+        None => 0,
+    };
+
+    if version > STATE_VERSION {
+        return Err(format!("state version {} is newer than supported {}", version, STATE_VERSION));
+    }
+
+    while version < STATE_VERSION {
+        doc = match version {
+            0 => {
+                let mut m = Map::new();
+                m.insert("kbdata".to_owned(), doc);
+                Value::Object(m)
+            },
+            // publication time was not recorded, start retention period from now
+            1 => {
+                let now = Value::from(time::get_time().sec);
+                if let Some(&mut Value::Object(ref mut kbdata)) = doc.get_mut("kbdata") {
+                    for kb in kbdata.values_mut() {
+                        if let Value::Object(ref mut kb) = *kb {
+                            kb.entry("published_at").or_insert_with(|| now.clone());
+                        }
+                    }
+                }
+                doc
+            },
+            2 => {
+                if let Some(&mut Value::Object(ref mut kbdata)) = doc.get_mut("kbdata") {
+                    upgrade_votes(kbdata);
+                }
+                doc
+            },
+            _ => unreachable!(),
+        };
+        version += 1;
+        info!("upgraded state document to version {}", version);
+    }
+
+    if let Value::Object(ref mut m) = doc {
+        m.insert("version".to_owned(), Value::from(version));
+    }
+
+    Ok(doc)
+}
+
+// Single list of votes becomes "like" votes when reactions are introduced.
+fn upgrade_votes(kbdata: &mut Map<String, Value>) {
+    for kb in kbdata.values_mut() {
+        if let Some(votes) = kb.get_mut("votes") {
+            if votes.is_array() {
+                let mut m = Map::new();
+                m.insert("like".to_owned(), votes.take());
+                *votes = Value::Object(m);
+            }
+        }
+    }
+}
+
+// Archive has no version of its own and may still hold entries
+// written by older bot versions.
+pub fn load_archive(path: &str) -> Result<HashMap<PostId, KbData>, String> {
+    let mut archive = load_json::<Value>(path)?;
+
+    if let Value::Object(ref mut kbdata) = archive {
+        upgrade_votes(kbdata);
+    }
+
+    serde_json::from_value(archive).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn likes(doc: &StateDoc, id: PostId) -> Vec<i32> {
+        doc.kbdata[&id].votes.get("like").cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn upgrades_bare_map() {
+        let doc = upgrade_state(json!({"5": {"url": "u", "votes": [1, 2]}})).unwrap();
+        assert_eq!(doc.version, STATE_VERSION);
+        assert_eq!(likes(&doc, 5), vec![1, 2]);
+        assert!(doc.kbdata[&5].published_at > 0);
+        assert!(doc.top_ids.is_empty());
+    }
+
+    #[test]
+    fn upgrades_version_1() {
+        let doc = upgrade_state(json!({
+            "version": 1,
+            "kbdata": {"5": {"url": "u", "votes": [3]}},
+            "top_ids": [7],
+        })).unwrap();
+        assert_eq!(likes(&doc, 5), vec![3]);
+        assert!(doc.kbdata[&5].published_at > 0);
+        assert_eq!(doc.top_ids, vec![7]);
+    }
+
+    #[test]
+    fn upgrades_version_2() {
+        let doc = upgrade_state(json!({
+            "version": 2,
+            "kbdata": {"5": {"url": "u", "votes": [3], "published_at": 100}},
+        })).unwrap();
+        assert_eq!(likes(&doc, 5), vec![3]);
+        assert_eq!(doc.kbdata[&5].published_at, 100);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        assert!(upgrade_state(json!({"version": STATE_VERSION + 1, "kbdata": {}})).is_err());
+        assert!(upgrade_state(json!({"version": "x", "kbdata": {}})).is_err());
+    }
+
+    #[test]
+    fn merge_keeps_missing_fields() {
+        let bs = BotState {
+            top_ids: vec![7],
+            last_polled_id: 42,
+            pinned: Some(9),
+            ..BotState::default()
+        };
+
+        let doc = merge_state(json!({"5": {"url": "u", "votes": [1]}}), &bs).unwrap();
+        assert_eq!(likes(&doc, 5), vec![1]);
+        assert_eq!(doc.top_ids, vec![7]);
+        assert_eq!(doc.last_polled_id, 42);
+        assert_eq!(doc.pinned, Some(9));
+
+        let doc = merge_state(json!({"version": STATE_VERSION, "kbdata": {}, "top_ids": []}), &bs).unwrap();
+        assert!(doc.top_ids.is_empty());
+        assert_eq!(doc.last_polled_id, 42);
+    }
+}
@@ -0,0 +1,1118 @@
+use iron::prelude::*;
+use persistent::{Read, State};
+use bodyparser;
+use time;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use telegram::*;
+use fish::{self, RfApi, RfReportInfo};
+use routing;
+use storage::{Storage, PostRecord};
+use telegraph::{self, TphApi};
+use {BotState, BotStorage, Config, InFlight, KbData, Layout, PostId, SafeBotState, SafeStorage};
+use {add_to_digest, api_result, chat_id, get_info_for, in_hours, json_response, log_db, make_report_kb};
+
+// On failure tells whether text was already sent.
+pub fn send_announcement(tg: &TgBotApi, chat: &TgChatId, text: Option<&String>, images: &[String]) -> Result<(), (bool, String)> {
+    if let Some(text) = text {
+        api_result(tg.send_md_text(text.clone(), chat.clone(), None)).map_err(|e| (false, e))?;
+    }
+    if !images.is_empty() {
+        api_result(tg.send_album(images.iter(), chat.clone(), false)).map_err(|e| (true, e))?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RetryOp {
+    // chats is None if report was not posted anywhere yet, `force` posts it
+    // again even if it was published before
+    Publish { report_id: i32, chats: Option<Vec<String>>, #[serde(default)] force: bool },
+    // text is None if it was already sent
+    Announce { chat: TgChatId, text: Option<String>, images: Vec<String> },
+}
+
+// Operation that would finish failed job. It is `permanent` if doing it
+// again won't help, e.g. Bot API rejected the chat or the message.
+pub struct Failure {
+    pub op: RetryOp,
+    pub error: String,
+    pub permanent: bool,
+}
+
+// Bot API answered with 4xx other than 429 Too Many Requests. Network errors,
+// 5xx and flood limits are worth retrying.
+pub fn is_permanent_error(err: &str) -> bool {
+    err.strip_prefix("Bot API error ")
+        .and_then(|e| e.split(':').next())
+        .and_then(|code| code.parse::<i32>().ok())
+        .map_or(false, |code| (400..500).contains(&code) && code != 429)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetryEntry {
+    id: u64,
+    op: RetryOp,
+    attempts: u32,
+    next_at: i64,
+    last_error: String,
+    created_at: i64,
+}
+
+fn retry_backoff(attempts: u32, cfg: &Config) -> i64 {
+    cfg.retrydelay << std::cmp::min(attempts.saturating_sub(1), 10)
+}
+
+// Failed operation counts as first attempt, permanent failure goes to dead letters.
+pub fn queue_retry(st: &SafeBotState, failure: Failure, cfg: &Config) -> RetryEntry {
+    let now = time::get_time().sec;
+    let mut entry = RetryEntry {
+        id: 0,
+        op: failure.op,
+        attempts: 1,
+        next_at: now + retry_backoff(1, cfg),
+        last_error: failure.error,
+        created_at: now,
+    };
+
+    if let Ok(mut guard) = st.write() {
+        let bs = &mut *guard;
+        bs.retry_seq += 1;
+        entry.id = bs.retry_seq;
+        if failure.permanent || entry.attempts >= cfg.retryattempts {
+            bs.dead_letters.push(entry.clone());
+        } else {
+            bs.retries.push(entry.clone());
+        }
+        bs.state_rev += 1;
+    }
+
+    entry
+}
+
+// Publish retry has nothing left to do if report got its messages by other
+// means meanwhile or, when resuming, if it was unpublished.
+fn publish_settled(published: &HashMap<i32, Published>, report_id: i32, resuming: bool) -> bool {
+    match published.get(&report_id) {
+        Some(p) => !resuming && !p.is_empty(),
+        None => resuming,
+    }
+}
+
+fn run_retry(st: &SafeBotState, db: &dyn Storage, op: &RetryOp, cfg: &Config) -> Result<(), Failure> {
+    match *op {
+        RetryOp::Publish { report_id, ref chats, force } => {
+            match st.write() {
+                Ok(mut guard) => {
+                    let bs = &mut *guard;
+                    if !force && publish_settled(&bs.published, report_id, chats.is_some()) {
+                        return Ok(());
+                    }
+                    if !bs.publishing.insert(report_id) {
+                        return Err(Failure { op: op.clone(), error: "report is being published right now".to_owned(), permanent: false });
+                    }
+                },
+                Err(e) => return Err(Failure { op: op.clone(), error: e.to_string(), permanent: false }),
+            }
+
+            // site may be down for a while, report is not given up on yet
+            let res = match RfApi::new().fetch_report_info(report_id) {
+                Some(ri) => post_report(st, db, &ri, chats.as_ref().map(|c| &c[..]), force, cfg).map(|_| ()),
+                None => Err(Failure { op: op.clone(), error: "unable to fetch report".to_owned(), permanent: false }),
+            };
+
+            if let Ok(mut guard) = st.write() {
+                guard.publishing.remove(&report_id);
+            }
+            res
+        },
+        RetryOp::Announce { ref chat, ref text, ref images } => {
+            let tg = TgBotApi::new(&cfg.bottoken);
+            send_announcement(&tg, chat, text.as_ref(), images).map_err(|(text_sent, err)| Failure {
+                op: RetryOp::Announce {
+                    chat: chat.clone(),
+                    text: if text_sent { None } else { text.clone() },
+                    images: images.clone(),
+                },
+                permanent: is_permanent_error(&err),
+                error: err,
+            })
+        },
+    }
+}
+
+// Due entries stay in queue while they run, so they survive restart.
+fn take_due_retries(st: &SafeBotState, cfg: &Config) -> Vec<RetryEntry> {
+    let now = time::get_time().sec;
+    match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+            let due = bs.retries.iter_mut()
+                .filter(|r| r.next_at <= now)
+                .map(|r| {
+                    r.next_at = now + retry_backoff(r.attempts + 1, cfg);
+                    r.clone()
+                })
+                .collect::<Vec<_>>();
+            if !due.is_empty() {
+                bs.state_rev += 1;
+            }
+            due
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn spawn_retrier(st: SafeBotState, db: SafeStorage, inflight: Arc<InFlight>, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(10));
+
+        for entry in take_due_retries(&st, cfg) {
+            let _work = match inflight.enter() {
+                Some(g) => g,
+                None => return, // shutting down, entry runs after restart
+            };
+            let res = run_retry(&st, &**db, &entry.op, cfg);
+
+            if let Ok(mut guard) = st.write() {
+                let bs = &mut *guard;
+                let pos = match bs.retries.iter().position(|r| r.id == entry.id) {
+                    Some(pos) => pos,
+                    None => continue, // discarded meanwhile
+                };
+                bs.state_rev += 1;
+
+                match res {
+                    Ok(()) => {
+                        info!("retry #{}: done after {} attempt(s)", entry.id, entry.attempts + 1);
+                        bs.retries.remove(pos);
+                    },
+                    Err(f) => {
+                        let mut r = bs.retries.remove(pos);
+                        r.op = f.op;
+                        r.attempts += 1;
+                        r.last_error = f.error;
+                        if f.permanent || r.attempts >= cfg.retryattempts {
+                            error!("retry #{}: giving up after {} attempts: {}", r.id, r.attempts, r.last_error);
+                            bs.dead_letters.push(r);
+                        } else {
+                            warn!("retry #{}: attempt {} failed: {}", r.id, r.attempts, r.last_error);
+                            r.next_at = time::get_time().sec + retry_backoff(r.attempts, cfg);
+                            bs.retries.push(r);
+                        }
+                    },
+                }
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct RetryList<'a> {
+    pending: &'a [RetryEntry],
+    dead: &'a [RetryEntry],
+}
+
+pub fn retries(req: &mut Request) -> IronResult<Response> {
+    if let Ok(arc_st) = req.get::<State<BotState>>() {
+        if let Ok(guard) = arc_st.read() {
+            return Ok(json_response(&RetryList {
+                pending: &guard.retries,
+                dead: &guard.dead_letters,
+            }));
+        }
+    }
+
+    Ok(Response::with(iron::status::InternalServerError))
+}
+
+#[derive(Deserialize, Clone)]
+struct RetryRef {
+    id: u64,
+}
+
+// Runs entry again as soon as possible, dead ones get full number of attempts again.
+pub fn retry_now(bs: &mut BotState, id: u64) -> bool {
+    let now = time::get_time().sec;
+    if let Some(r) = bs.retries.iter_mut().find(|r| r.id == id) {
+        r.next_at = now;
+        return true;
+    }
+
+    match bs.dead_letters.iter().position(|r| r.id == id) {
+        Some(pos) => {
+            let mut r = bs.dead_letters.remove(pos);
+            r.attempts = 0;
+            r.next_at = now;
+            bs.retries.push(r);
+            true
+        },
+        None => false,
+    }
+}
+
+pub fn discard_retry(bs: &mut BotState, id: u64) -> bool {
+    let before = bs.retries.len() + bs.dead_letters.len();
+    bs.retries.retain(|r| r.id != id);
+    bs.dead_letters.retain(|r| r.id != id);
+    before != bs.retries.len() + bs.dead_letters.len()
+}
+
+pub fn change_retry(req: &mut Request, name: &str, f: fn(&mut BotState, u64) -> bool) -> IronResult<Response> {
+    let resp = match req.get::<bodyparser::Struct<RetryRef>>() {
+        Ok(Some(r)) => match req.get::<State<BotState>>() {
+            Ok(arc_st) => match arc_st.write() {
+                Ok(mut guard) => if f(&mut *guard, r.id) {
+                    guard.state_rev += 1;
+                    info!("{}: retry #{}", name, r.id);
+                    Response::with(iron::status::Ok)
+                } else {
+                    info!("{}: no retry #{}", name, r.id);
+                    Response::with(iron::status::NotFound)
+                },
+                Err(_) => Response::with(iron::status::InternalServerError),
+            },
+            Err(_) => Response::with(iron::status::InternalServerError),
+        },
+        Ok(None) => {
+            info!("{}: request has empty body", name);
+            Response::with(iron::status::BadRequest)
+        },
+        Err(err) => {
+            error!("{}: {:?} while parsing request body", name, err);
+            Response::with(iron::status::BadRequest)
+        },
+    };
+
+    Ok(resp)
+}
+
+#[derive(Deserialize, Clone)]
+struct PublishReport {
+    id: i32,
+    #[serde(default)]
+    force: bool,
+    publish_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueuedReport {
+    pub report_id: i32,
+    pub publish_at: i64,
+    pub force: bool,
+    pub queued_at: i64,
+}
+
+// Messages bot created in a chat other than `cfg.channel`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatPost {
+    pub chat: String,
+    pub card: PostId,
+    pub album: Vec<PostId>,
+}
+
+// Messages bot created for a report. Only the card in `cfg.channel` has vote buttons.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Published {
+    pub card: Option<PostId>,
+    pub album: Vec<PostId>,
+    pub published_at: i64,
+    #[serde(default)]
+    pub routed: Vec<ChatPost>,
+    #[serde(default)]
+    pub captioned: bool, // report text is caption of first album photo
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub earlier: Vec<Published>, // posted before for the same report with `force`, oldest first
+}
+
+impl Published {
+    // Nothing posted yet. Messages of `prior` post are kept in `earlier`.
+    fn replacing(prior: Option<Published>, published_at: i64, captioned: bool) -> Published {
+        let earlier = match prior {
+            Some(mut p) => {
+                let mut earlier = std::mem::take(&mut p.earlier);
+                earlier.push(p);
+                earlier
+            },
+            None => Vec::new(),
+        };
+
+        Published {
+            card: None,
+            album: Vec::new(),
+            published_at,
+            routed: Vec::new(),
+            captioned,
+            earlier,
+        }
+    }
+
+    // This post and all posted earlier.
+    pub fn sets(&self) -> Vec<&Published> {
+        self.earlier.iter().chain(Some(self)).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.card.is_none() && self.album.is_empty() && self.routed.is_empty()
+    }
+
+    fn sent_to(&self, chat: &str, cfg: &Config) -> ChatSent {
+        if chat == cfg.channel {
+            return ChatSent { card: self.card, album: self.album.clone(), error: None };
+        }
+
+        match self.routed.iter().find(|p| p.chat == chat) {
+            // card of captioned post is its first photo
+            Some(p) if self.captioned => ChatSent {
+                card: None,
+                album: Some(p.card).into_iter().chain(p.album.iter().cloned()).collect(),
+                error: None,
+            },
+            Some(p) => ChatSent { card: Some(p.card), album: p.album.clone(), error: None },
+            None => ChatSent::default(),
+        }
+    }
+
+    fn set_sent(&mut self, chat: &str, sent: &ChatSent, cfg: &Config) {
+        if chat == cfg.channel {
+            self.card = sent.card;
+            self.album = sent.album.clone();
+            return;
+        }
+
+        self.routed.retain(|p| p.chat != chat);
+        let mut ids = sent.card.into_iter().chain(sent.album.iter().cloned()).collect::<Vec<_>>();
+        if !ids.is_empty() {
+            let card = ids.remove(0);
+            self.routed.push(ChatPost {
+                chat: chat.to_owned(),
+                card,
+                album: ids,
+            });
+        }
+    }
+
+    pub fn messages(&self, cfg: &Config) -> Vec<(String, PostId)> {
+        let home = self.card.iter().chain(self.album.iter()).map(|&id| (cfg.channel.clone(), id));
+        let routed = self.routed.iter()
+            .flat_map(|p| Some(p.card).into_iter().chain(p.album.iter().cloned()).map(move |id| (p.chat.clone(), id)));
+        home.chain(routed).collect()
+    }
+}
+
+// What was posted to one chat, `error` is set if posting stopped half way.
+#[derive(Default)]
+pub struct ChatSent {
+    pub card: Option<PostId>,
+    pub album: Vec<PostId>,
+    pub error: Option<String>,
+}
+
+pub const LINK_TEXT: &str = "переглянути на вебсайті";
+
+pub fn make_caption(text: &str, url: &str) -> String {
+    format!("{}\n\n<a href=\"{}\">{}</a>", text, url, LINK_TEXT)
+}
+
+// Too long caption falls back to separate card and album.
+pub fn fits_caption(ri: &RfReportInfo, caption: &str, cfg: &Config) -> bool {
+    cfg.layout == Layout::Album && ri.photos.len() >= 2 && caption.chars().count() <= MAX_CAPTION_LEN
+}
+
+// Bot API errors telling that Telegram could not fetch a file by URL itself,
+// or does not know file_id we sent ("wrong file identifier/HTTP URL specified").
+fn is_url_fetch_error(description: &str) -> bool {
+    let d = description.to_lowercase();
+    d.contains("failed to get http url content")
+        || d.contains("wrong file identifier/http url specified")
+        || d.contains("wrong type of the web page content")
+        || d.contains("webpage_")
+}
+
+// Sends photos by URL, or by file_id if they were uploaded before. If Telegram
+// can't fetch them itself, photos are downloaded and uploaded by the bot.
+fn send_photos(st: &SafeBotState, tg: &TgBotApi, chat: &str, urls: &[String], caption: Option<String>, silent: bool)
+-> Result<Vec<PostId>, String> {
+    let cached = match st.read() {
+        Ok(g) => urls.iter().map(|u| g.photo_ids.get(u).cloned()).collect::<Vec<_>>(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let refs = urls.iter().zip(&cached)
+        .map(|(u, c)| TgInputFile::Ref(c.clone().unwrap_or_else(|| u.clone())))
+        .collect();
+
+    let msgs = match tg.send_media_group(refs, caption.clone(), chat_id(chat), silent) {
+        Ok(TgResponse {ok: false, description: Some(ref d), ..}) if is_url_fetch_error(d) => {
+            warn!("album for {}: Bot API error: {:?}, uploading photos", chat, d);
+            // any of cached ids may be the stale one, forget them all
+            if cached.iter().any(Option::is_some) {
+                if let Ok(mut bs) = st.write() {
+                    for url in urls {
+                        bs.photo_ids.remove(url);
+                    }
+                    bs.state_rev += 1;
+                }
+            }
+            let fish = RfApi::new();
+            let files = urls.iter()
+                .map(|u| fish.fetch_photo(u).map(TgInputFile::Bytes).map_err(|e| format!("downloading {}: {}", u, e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            api_result(tg.send_media_group(files, caption, chat_id(chat), silent))?
+        },
+        r => api_result(r)?,
+    };
+
+    if let Ok(mut guard) = st.write() {
+        let bs = &mut *guard;
+        for (url, m) in urls.iter().zip(&msgs) {
+            if let Some(p) = m.photo.last() {
+                if bs.photo_ids.get(url) != Some(&p.file_id) {
+                    bs.photo_ids.insert(url.clone(), p.file_id.clone());
+                    bs.state_rev += 1;
+                }
+            }
+        }
+    }
+
+    Ok(msgs.iter().map(|m| m.message_id).collect())
+}
+
+// Report post for one chat. `text` is card text, or album caption.
+pub struct ChatCard<'a> {
+    pub chat: &'a str,
+    pub ri: &'a RfReportInfo,
+    pub text: String,
+    pub home: bool, // `cfg.channel`, gets vote buttons
+    pub silent: bool,
+}
+
+// Text card, followed by album of photos if layout asks for it.
+// Parts already in `sent` are not posted again.
+pub fn send_card(st: &SafeBotState, tg: &TgBotApi, card: &ChatCard, mut sent: ChatSent, cfg: &Config) -> ChatSent {
+    let ChatCard { chat, ri, home, silent, .. } = *card;
+    let kb = if home {
+        None
+    } else {
+        Some(TgInlineKeyboardMarkup::url_button(LINK_TEXT.to_owned(), ri.url.clone()))
+    };
+
+    if sent.card.is_none() {
+        match api_result(tg.send_rich_text(card.text.clone(), chat_id(chat), kb, silent)) {
+            Ok(msg) => sent.card = Some(msg.message_id),
+            Err(err) => {
+                sent.error = Some(err);
+                return sent;
+            },
+        }
+    }
+
+    if ri.photos.len() < 2 || cfg.layout == Layout::Card || !sent.album.is_empty() {
+        return sent;
+    }
+
+    let urls = ri.photos.iter().map(|p| p.medium_url.clone()).collect::<Vec<_>>();
+    match send_photos(st, tg, chat, &urls, None, silent) {
+        Ok(album) => sent.album = album,
+        Err(err) => sent.error = Some(format!("album: {}", err)),
+    }
+    sent
+}
+
+// Single album with caption. Albums can't have buttons, so in home channel
+// votes go to a short message replying to it. Parts already in `sent` are not posted again.
+pub fn send_captioned(st: &SafeBotState, tg: &TgBotApi, card: &ChatCard, mut sent: ChatSent) -> ChatSent {
+    let ChatCard { chat, ri, home, silent, .. } = *card;
+    if sent.album.is_empty() {
+        let urls = ri.photos.iter().map(|p| p.medium_url.clone()).collect::<Vec<_>>();
+        match send_photos(st, tg, chat, &urls, Some(card.text.clone()), silent) {
+            Ok(album) => sent.album = album,
+            Err(err) => {
+                sent.error = Some(err);
+                return sent;
+            },
+        }
+    }
+
+    if let (true, None, Some(&first)) = (home, sent.card, sent.album.first()) {
+        match api_result(tg.send_rich_reply(make_vote_text(ri), first, chat_id(chat), None, silent)) {
+            Ok(msg) => sent.card = Some(msg.message_id),
+            Err(err) => sent.error = Some(format!("vote message: {}", err)),
+        }
+    }
+    sent
+}
+
+pub fn make_vote_text(ri: &RfReportInfo) -> String {
+    format!("<b>{}</b>", ri.title)
+}
+
+// Telegraph page with full text of a long report, None if disabled or not needed.
+// Page is remembered before posting, so retries don't create it again.
+fn create_article(st: &SafeBotState, ri: &RfReportInfo, cfg: &Config) -> Option<String> {
+    if cfg.telegraphtoken.is_empty() || ri.description.chars().count() < cfg.telegraphminlen {
+        return None;
+    }
+    if let Some(url) = st.read().ok().and_then(|g| g.articles.get(&ri.id).cloned()) {
+        return Some(url);
+    }
+
+    let tph = TphApi::new(&cfg.telegraphurl, &cfg.telegraphtoken);
+    match tph.create_page(&ri.title, "rivnefish.com", &ri.url, &telegraph::report_content(ri)) {
+        Ok(url) => {
+            info!("created article {} for report #{}", url, ri.id);
+            if let Ok(mut guard) = st.write() {
+                guard.articles.insert(ri.id, url.clone());
+                guard.state_rev += 1;
+            }
+            Some(url)
+        },
+        Err(err) => {
+            // card is posted without the link
+            warn!("creating article for report #{}: {}", ri.id, err);
+            None
+        },
+    }
+}
+
+// Posts report to chats given by routing rules, or, when resuming, finishes
+// posting to `chats` only. On failure returns operation that would finish the job,
+// `force` is passed on to it.
+fn post_report(st: &SafeBotState, db: &dyn Storage, ri: &RfReportInfo, chats: Option<&[String]>, force: bool, cfg: &Config)
+-> Result<Published, Failure> {
+    let report_id = ri.id;
+    let retry_all = |err: String| Failure {
+        op: RetryOp::Publish { report_id, chats: chats.map(|c| c.to_vec()), force },
+        error: err,
+        permanent: false,
+    };
+
+    let prior = match st.read() {
+        Ok(g) => g.published.get(&report_id).cloned(),
+        Err(e) => return Err(retry_all(e.to_string())),
+    };
+
+    let fish = RfApi::new();
+    let pi = ri.place.as_ref().and_then(|p| get_info_for(st, &fish, p.id));
+    let tg = TgBotApi::new(&cfg.bottoken);
+    let kbdata = KbData::new(ri.url.clone(), Some(ri.title.clone()), Some(ri.id));
+    let silent = cfg.silenthours.map_or(false, |w| in_hours(time::now().tm_hour, w));
+    let article = create_article(st, ri, cfg);
+
+    let text = match st.read() {
+        Ok(g) => fish::get_report_text(ri, pi.as_ref(), &g.fishes, article.as_ref().map(|s| s.as_str())),
+        Err(e) => return Err(retry_all(e.to_string())),
+    };
+
+    let caption = make_caption(&text, &ri.url);
+    let captioned = fits_caption(ri, &caption, cfg);
+
+    let (mut published, targets) = match (chats, prior) {
+        (Some(chats), Some(prior)) => (prior, chats.to_vec()),
+        // posted again with `force`, earlier messages are kept
+        (chats, prior) => (Published::replacing(prior, kbdata.published_at, captioned), chats.map(|c| c.to_vec())
+            .unwrap_or_else(|| routing::route_report(&cfg.routes, ri, pi.as_ref(), &cfg.channel))),
+    };
+    let mut failed = Vec::new();
+    let mut errors = Vec::new();
+    let mut permanent = true; // every chat rejected what was sent to it
+
+    for chat in targets {
+        let home = chat == cfg.channel;
+        let prior = published.sent_to(&chat, cfg);
+        let had_card = prior.card.is_some();
+        let sent = if published.captioned {
+            send_captioned(st, &tg, &ChatCard { chat: &chat, ri, text: caption.clone(), home, silent }, prior)
+        } else {
+            send_card(st, &tg, &ChatCard { chat: &chat, ri, text: text.clone(), home, silent }, prior, cfg)
+        };
+
+        if let (true, false, Some(card)) = (home, had_card, sent.card) {
+            tg.update_kb(card, make_report_kb(&kbdata, card, cfg), chat_id(&chat));
+            log_db(db.record_post(&PostRecord {
+                message_id: card,
+                report_id: ri.id,
+                url: ri.url.clone(),
+                published_at: published.published_at,
+            }));
+            if cfg.pinfeatured && ri.report_fishes.iter().any(|f| f.featured) {
+                pin_featured(st, &tg, card, silent, cfg);
+            }
+        }
+        published.set_sent(&chat, &sent, cfg);
+
+        // report that has no messages yet is not published
+        if !published.is_empty() {
+            if let Ok(mut guard) = st.write() {
+                let bs = &mut *guard;
+                if let (true, Some(card)) = (home, published.card) {
+                    bs.kbdata.entry(card).or_insert_with(|| kbdata.clone());
+                }
+                bs.published.insert(ri.id, published.clone());
+                bs.state_rev += 1;
+            }
+        }
+
+        if let Some(err) = sent.error {
+            permanent &= is_permanent_error(&err);
+            errors.push(format!("{}: {}", chat, err));
+            failed.push(chat);
+        }
+    }
+
+    if chats.is_none() && !published.is_empty() {
+        log_db(db.bump_counter("published"));
+    }
+
+    if !errors.is_empty() {
+        let op = if chats.is_none() && published.is_empty() {
+            RetryOp::Publish { report_id, chats: None, force }
+        } else {
+            RetryOp::Publish { report_id, chats: Some(failed), force: false }
+        };
+        return Err(Failure { op, error: errors.join("; "), permanent });
+    }
+
+    info!("publish #{}: {} message(s) posted to {} chat(s)",
+        ri.id, published.messages(cfg).len(), published.routed.len() + published.card.iter().count());
+    Ok(published)
+}
+
+// Pins card of a report with featured catch, replacing the one pinned before.
+fn pin_featured(st: &SafeBotState, tg: &TgBotApi, card: PostId, silent: bool, cfg: &Config) {
+    if let Err(err) = api_result(tg.pin_msg(card, chat_id(&cfg.channel), silent)) {
+        error!("pinning message {}: {}", card, err);
+        return;
+    }
+    info!("pinned featured report message {}", card);
+
+    let prev = match st.write() {
+        Ok(mut guard) => {
+            guard.state_rev += 1;
+            guard.pinned.replace(card)
+        },
+        Err(_) => return,
+    };
+
+    if let Some(prev) = prev.filter(|&p| p != card) {
+        if let Err(err) = api_result(tg.unpin_msg(prev, chat_id(&cfg.channel))) {
+            warn!("unpinning message {}: {}", prev, err);
+        }
+    }
+}
+
+enum PublishError {
+    Failed(String),
+    NotFetched, // report could not be fetched from site, nothing was posted
+    Retrying(Box<RetryEntry>), // failed, but will be retried later
+    Rejected(Box<RetryEntry>), // Bot API refused it, entry is in dead letters
+}
+
+// Reports already published are not posted again unless `force` is set,
+// site webhook may call `/publish` several times for the same report.
+fn publish_report(st: &SafeBotState, db: &dyn Storage, report_id: i32, force: bool, cfg: &Config)
+-> Result<Published, PublishError> {
+    match st.write() {
+        Ok(mut guard) => {
+            let bs = &mut *guard;
+            if let (false, Some(p)) = (force, bs.published.get(&report_id)) {
+                info!("publish #{}: already published at {}", report_id, p.published_at);
+                return Ok(p.clone());
+            }
+            if !bs.publishing.insert(report_id) {
+                return Err(PublishError::Failed("report is being published right now".to_owned()));
+            }
+        },
+        Err(e) => return Err(PublishError::Failed(e.to_string())),
+    }
+
+    let res = match RfApi::new().fetch_report_info(report_id) {
+        Some(ri) => post_report(st, db, &ri, None, force, cfg).map_err(Some),
+        None => Err(None),
+    };
+
+    if let Ok(mut guard) = st.write() {
+        guard.publishing.remove(&report_id);
+    }
+
+    res.map_err(|f| match f {
+        Some(f) => {
+            error!("publish #{}: {}", report_id, f.error);
+            let permanent = f.permanent;
+            let entry = Box::new(queue_retry(st, f, cfg));
+            if permanent { PublishError::Rejected(entry) } else { PublishError::Retrying(entry) }
+        },
+        None => PublishError::NotFetched,
+    })
+}
+
+// Puts report into queue if it should not go out right now, returns queue entry.
+// Reports already in queue are rescheduled.
+fn schedule_report(st: &SafeBotState, p: &PublishReport, cfg: &Config) -> Result<Option<QueuedReport>, String> {
+    let now = time::get_time().sec;
+    let quiet = cfg.quiethours.map_or(false, |w| in_hours(time::now().tm_hour, w));
+    let publish_at = p.publish_at.unwrap_or(now);
+
+    let mut guard = st.write().map_err(|e| e.to_string())?;
+    let bs = &mut *guard;
+    let queued = bs.queue.iter().any(|q| q.report_id == p.id);
+
+    if publish_at <= now && !quiet && !queued {
+        return Ok(None);
+    }
+    if !p.force && bs.published.contains_key(&p.id) {
+        return Ok(None);
+    }
+
+    let q = QueuedReport {
+        report_id: p.id,
+        publish_at,
+        force: p.force,
+        queued_at: now,
+    };
+    bs.queue.retain(|q| q.report_id != p.id);
+    bs.queue.push(q.clone());
+    bs.queue.sort_by_key(|q| (q.publish_at, q.queued_at));
+    bs.state_rev += 1;
+
+    Ok(Some(q))
+}
+
+// Due reports stay in queue until they are published, so they survive restart.
+fn take_due_reports(st: &SafeBotState, cfg: &Config) -> Vec<QueuedReport> {
+    if cfg.quiethours.map_or(false, |w| in_hours(time::now().tm_hour, w)) {
+        return Vec::new();
+    }
+
+    let now = time::get_time().sec;
+    match st.write() {
+        Ok(guard) => guard.queue.iter().take_while(|q| q.publish_at <= now).cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Entry rescheduled meanwhile is kept.
+fn remove_queued(st: &SafeBotState, q: &QueuedReport) {
+    if let Ok(mut guard) = st.write() {
+        let bs = &mut *guard;
+        let len = bs.queue.len();
+        bs.queue.retain(|e| e.report_id != q.report_id || e.queued_at != q.queued_at);
+        if bs.queue.len() != len {
+            bs.state_rev += 1;
+        }
+    }
+}
+
+pub fn spawn_publish_queue(st: SafeBotState, db: SafeStorage, inflight: Arc<InFlight>, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(30));
+
+        for q in take_due_reports(&st, cfg) {
+            let _work = match inflight.enter() {
+                Some(g) => g,
+                None => return, // shutting down, report stays queued
+            };
+            match publish_report(&st, &**db, q.report_id, q.force, cfg) {
+                Ok(_) => info!("queue #{}: published", q.report_id),
+                // stays in queue, tried again on next round
+                Err(PublishError::Failed(err)) => {
+                    error!("queue #{}: {}", q.report_id, err);
+                    continue;
+                },
+                // was accepted already, so site gets time to come back
+                Err(PublishError::NotFetched) => {
+                    let op = RetryOp::Publish { report_id: q.report_id, chats: None, force: q.force };
+                    let r = queue_retry(&st, Failure { op, error: "unable to fetch report".to_owned(), permanent: false }, cfg);
+                    warn!("queue #{}: unable to fetch report, will be retried as #{}", q.report_id, r.id);
+                },
+                Err(PublishError::Retrying(r)) => info!("queue #{}: will be retried as #{}", q.report_id, r.id),
+                Err(PublishError::Rejected(r)) => error!("queue #{}: rejected, moved to dead letters as #{}", q.report_id, r.id),
+            }
+            remove_queued(&st, &q);
+        }
+    });
+}
+
+enum Submitted {
+    Digest,
+    Queued(QueuedReport),
+    Published(Published),
+}
+
+// Everything `/publish` does with a report: digest, queue or posting right away.
+fn submit_report(st: &SafeBotState, db: &dyn Storage, p: &PublishReport, cfg: &Config) -> Result<Submitted, PublishError> {
+    if add_to_digest(st, p.id, cfg) {
+        return Ok(Submitted::Digest);
+    }
+
+    match schedule_report(st, p, cfg) {
+        Ok(Some(q)) => Ok(Submitted::Queued(q)),
+        Ok(None) => publish_report(st, db, p.id, p.force, cfg).map(Submitted::Published),
+        Err(err) => Err(PublishError::Failed(err)),
+    }
+}
+
+pub fn publish(req: &mut Request, cfg: &Config) -> IronResult<Response> {
+    let resp = match req.get::<bodyparser::Struct<PublishReport>>() {
+        Ok(Some(p)) => match (req.get::<State<BotState>>(), req.get::<Read<BotStorage>>()) {
+            (Ok(arc_st), Ok(db)) => match submit_report(&arc_st, &**db, &p, cfg) {
+                Ok(Submitted::Digest) => {
+                    info!("/publish #{}: added to digest", p.id);
+                    Response::with(iron::status::Accepted)
+                },
+                Ok(Submitted::Queued(q)) => {
+                    info!("/publish #{}: queued until {}", p.id, q.publish_at);
+                    let mut resp = json_response(&q);
+                    resp.status = Some(iron::status::Accepted);
+                    resp
+                },
+                Ok(Submitted::Published(published)) => json_response(&published),
+                Err(PublishError::Failed(err)) => {
+                    error!("/publish #{}: {}", p.id, err);
+                    Response::with(iron::status::InternalServerError)
+                },
+                Err(PublishError::NotFetched) => {
+                    error!("/publish #{}: unable to fetch report", p.id);
+                    Response::with(iron::status::InternalServerError)
+                },
+                Err(PublishError::Retrying(r)) => {
+                    info!("/publish #{}: will be retried as #{}", p.id, r.id);
+                    let mut resp = json_response(&r);
+                    resp.status = Some(iron::status::Accepted);
+                    resp
+                },
+                Err(PublishError::Rejected(r)) => {
+                    error!("/publish #{}: rejected, moved to dead letters as #{}", p.id, r.id);
+                    let mut resp = json_response(&r);
+                    resp.status = Some(iron::status::InternalServerError);
+                    resp
+                },
+            },
+            _ => Response::with(iron::status::InternalServerError),
+        },
+        Ok(None) => {
+            info!("/publish: request has empty body");
+            Response::with(iron::status::BadRequest)
+        },
+        Err(err) => {
+            error!("/publish: {:?} while parsing request body", err);
+            Response::with(iron::status::BadRequest)
+        },
+    };
+
+    Ok(resp)
+}
+
+// Reports with ids above the last one seen go through the same path as `/publish`.
+// First poll only remembers where to start from.
+fn poll_reports(st: &SafeBotState, db: &dyn Storage, inflight: &InFlight, cfg: &Config) {
+    let reports = match RfApi::new().fetch_recent_reports() {
+        Some(rs) => rs,
+        None => return,
+    };
+    let last = match st.read() {
+        Ok(g) => g.last_polled_id,
+        Err(_) => return,
+    };
+
+    let mut ids = reports.iter().map(|r| r.id).filter(|&id| id > last).collect::<Vec<_>>();
+    ids.sort();
+
+    if last == 0 {
+        if let (Some(&max), Ok(mut guard)) = (ids.last(), st.write()) {
+            guard.last_polled_id = max;
+            guard.state_rev += 1;
+            info!("poll: starting after report #{}", max);
+        }
+        return;
+    }
+
+    for id in ids {
+        // the rest is picked up by next poll after restart
+        let _work = match inflight.enter() {
+            Some(g) => g,
+            None => return,
+        };
+        let p = PublishReport { id, force: false, publish_at: None };
+        match submit_report(st, db, &p, cfg) {
+            Ok(Submitted::Digest) => info!("poll #{}: added to digest", id),
+            Ok(Submitted::Queued(q)) => info!("poll #{}: queued until {}", id, q.publish_at),
+            Ok(Submitted::Published(_)) => info!("poll #{}: published", id),
+            Err(PublishError::Failed(err)) => error!("poll #{}: {}", id, err),
+            Err(PublishError::NotFetched) => error!("poll #{}: unable to fetch report", id),
+            Err(PublishError::Retrying(r)) => info!("poll #{}: will be retried as #{}", id, r.id),
+            Err(PublishError::Rejected(r)) => error!("poll #{}: rejected, moved to dead letters as #{}", id, r.id),
+        }
+
+        if let Ok(mut guard) = st.write() {
+            guard.last_polled_id = id;
+            guard.state_rev += 1;
+        }
+    }
+}
+
+pub fn spawn_poller(st: SafeBotState, db: SafeStorage, inflight: Arc<InFlight>, cfg: &'static Config) {
+    thread::spawn(move || loop {
+        poll_reports(&st, &**db, &inflight, cfg);
+        thread::sleep(Duration::from_secs(cfg.pollperiod));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use storage;
+    use testutil;
+
+    fn new_state() -> SafeBotState {
+        Arc::new(RwLock::new(BotState::default()))
+    }
+
+    fn published(card: Option<PostId>) -> Published {
+        Published {
+            card,
+            album: Vec::new(),
+            published_at: 0,
+            routed: Vec::new(),
+            captioned: false,
+            earlier: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn due_reports_stay_queued() {
+        let cfg = testutil::config();
+        let st = new_state();
+        let now = time::get_time().sec;
+        st.write().unwrap().queue = vec![
+            QueuedReport { report_id: 1, publish_at: now - 10, force: false, queued_at: now - 20 },
+            QueuedReport { report_id: 2, publish_at: now + 3600, force: false, queued_at: now - 20 },
+        ];
+
+        let due = take_due_reports(&st, &cfg);
+        assert_eq!(due.iter().map(|q| q.report_id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(st.read().unwrap().queue.len(), 2);
+
+        // rescheduled while being published
+        st.write().unwrap().queue[0].queued_at = now;
+        remove_queued(&st, &due[0]);
+        assert_eq!(st.read().unwrap().queue.len(), 2);
+
+        st.write().unwrap().queue[0].queued_at = due[0].queued_at;
+        remove_queued(&st, &due[0]);
+        assert_eq!(st.read().unwrap().queue.iter().map(|q| q.report_id).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn url_fetch_errors() {
+        assert!(is_url_fetch_error("Bad Request: failed to get HTTP URL content"));
+        assert!(is_url_fetch_error("Bad Request: wrong file identifier/HTTP URL specified"));
+        assert!(is_url_fetch_error("Bad Request: WEBPAGE_CURL_FAILED"));
+        assert!(!is_url_fetch_error("Too Many Requests: retry after 5"));
+        assert!(!is_url_fetch_error("Bad Request: chat not found"));
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(is_permanent_error("Bot API error 400: Some(\"Bad Request: chat not found\")"));
+        assert!(is_permanent_error("Bot API error 403: Some(\"Forbidden: bot is not a member of the channel chat\")"));
+        assert!(!is_permanent_error("Bot API error 429: Some(\"Too Many Requests: retry after 5\")"));
+        assert!(!is_permanent_error("Bot API error 502: Some(\"Bad Gateway\")"));
+        assert!(!is_permanent_error("Bot API error 0: None"));
+        assert!(!is_permanent_error("error sending request: connection refused"));
+    }
+
+    #[test]
+    fn permanent_failure_is_dead_right_away() {
+        let cfg = testutil::config();
+        let st = new_state();
+        let op = RetryOp::Publish { report_id: 1, chats: Some(vec!["@gone".to_owned()]), force: false };
+        let entry = queue_retry(&st, Failure { op, error: "chat not found".to_owned(), permanent: true }, &cfg);
+
+        let bs = st.read().unwrap();
+        assert!(bs.retries.is_empty());
+        assert_eq!(bs.dead_letters.iter().map(|r| r.id).collect::<Vec<_>>(), vec![entry.id]);
+    }
+
+    #[test]
+    fn retry_backoff_doubles() {
+        let cfg = testutil::config();
+        let d = cfg.retrydelay;
+        assert_eq!(retry_backoff(1, &cfg), d);
+        assert_eq!(retry_backoff(2, &cfg), 2 * d);
+        assert_eq!(retry_backoff(3, &cfg), 4 * d);
+        assert_eq!(retry_backoff(100, &cfg), d << 10);
+    }
+
+    #[test]
+    fn publish_settled_needs_messages() {
+        let mut map = HashMap::new();
+        assert!(!publish_settled(&map, 1, false));
+        assert!(publish_settled(&map, 1, true));
+
+        map.insert(1, published(None));
+        assert!(!publish_settled(&map, 1, false));
+
+        map.insert(1, published(Some(10)));
+        assert!(publish_settled(&map, 1, false));
+        assert!(!publish_settled(&map, 1, true));
+    }
+
+    #[test]
+    fn force_keeps_earlier_posts() {
+        let cfg = testutil::config();
+        let mut first = published(Some(10));
+        first.album = vec![11, 12];
+        let mut second = Published::replacing(Some(first), 100, false);
+        assert!(second.is_empty());
+        second.set_sent("@other", &ChatSent { card: Some(5), album: Vec::new(), error: None }, &cfg);
+        second.set_sent(&cfg.channel, &ChatSent { card: Some(20), album: Vec::new(), error: None }, &cfg);
+
+        let third = Published::replacing(Some(second), 200, false);
+        assert_eq!(third.published_at, 200);
+        assert_eq!(third.sets().iter().map(|p| p.card).collect::<Vec<_>>(), vec![Some(10), Some(20), None]);
+        assert!(third.earlier.iter().all(|p| p.earlier.is_empty()));
+
+        let messages = third.sets().iter().flat_map(|p| p.messages(&cfg)).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            ("@channel".to_owned(), 10), ("@channel".to_owned(), 11), ("@channel".to_owned(), 12),
+            ("@channel".to_owned(), 20), ("@other".to_owned(), 5),
+        ]);
+    }
+
+    #[test]
+    fn publish_retry_done_when_posted_meanwhile() {
+        let cfg = testutil::config();
+        let st = new_state();
+        st.write().unwrap().published.insert(1, published(Some(10)));
+        let db = storage::MemStorage::default();
+
+        let op = RetryOp::Publish { report_id: 1, chats: None, force: false };
+        assert!(run_retry(&st, &db, &op, &cfg).is_ok());
+        assert!(st.read().unwrap().publishing.is_empty());
+    }
+
+    #[test]
+    fn due_retries_stay_queued() {
+        let cfg = testutil::config();
+        let st = new_state();
+        let op = RetryOp::Announce { chat: TgChatId::Integer(1), text: Some("text".to_owned()), images: Vec::new() };
+        let entry = queue_retry(&st, Failure { op, error: "error".to_owned(), permanent: false }, &cfg);
+        assert_eq!(entry.attempts, 1);
+        assert!(take_due_retries(&st, &cfg).is_empty());
+
+        st.write().unwrap().retries[0].next_at = 0;
+        let due = take_due_retries(&st, &cfg);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, entry.id);
+
+        let bs = st.read().unwrap();
+        assert_eq!(bs.retries.len(), 1);
+        assert!(bs.retries[0].next_at > 0);
+    }
+}
@@ -0,0 +1,115 @@
+use fish::{RfPlaceInfo, RfReportInfo};
+
+// Report goes to `chats` of every rule it matches. Rule matches if each
+// of its non-empty lists has at least one entry matching the report.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Route {
+    pub chats: Vec<String>,
+    #[serde(default)]
+    pub places: Vec<i32>,
+    #[serde(default)]
+    pub regions: Vec<String>, // substrings of place address
+    #[serde(default)]
+    pub fishing_types: Vec<String>,
+    #[serde(default)]
+    pub fishes: Vec<u32>,
+}
+
+impl Route {
+    pub fn matches(&self, report: &RfReportInfo, place: Option<&RfPlaceInfo>) -> bool {
+        let place_ok = self.places.is_empty() || report.place.as_ref()
+            .map_or(false, |p| self.places.contains(&p.id));
+
+        let address = place.map(|p| p.desc_short.to_lowercase()).unwrap_or_default();
+        let region_ok = self.regions.is_empty() || self.regions.iter()
+            .any(|r| address.contains(&r.to_lowercase()));
+
+        let types_ok = self.fishing_types.is_empty() || report.fishing_types.iter()
+            .any(|t| self.fishing_types.iter().any(|ft| ft.to_lowercase() == t.name.to_lowercase()));
+
+        let fishes_ok = self.fishes.is_empty() || report.report_fishes.iter()
+            .any(|f| self.fishes.contains(&f.fish_id));
+
+        place_ok && region_ok && types_ok && fishes_ok
+    }
+}
+
+// Chats report should be posted to, `default` if no rule matched.
+pub fn route_report(routes: &[Route], report: &RfReportInfo, place: Option<&RfPlaceInfo>, default: &str) -> Vec<String> {
+    let mut chats = Vec::new();
+
+    for route in routes.iter().filter(|r| r.matches(report, place)) {
+        for chat in &route.chats {
+            if !chats.contains(chat) {
+                chats.push(chat.clone());
+            }
+        }
+    }
+
+    if chats.is_empty() {
+        chats.push(default.to_owned());
+    }
+
+    chats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, json};
+
+    fn report() -> RfReportInfo {
+        serde_json::from_value(json!({
+            "id": 1,
+            "title": "t",
+            "short_description": "",
+            "url": "u",
+            "place": {"id": 20, "name": "Озеро"},
+            "photos": [],
+            "start_at": "",
+            "fishing_types": [{"name": "Фідер"}],
+            "report_fishes": [{"fish_id": 3, "featured": false, "baits": []}],
+        })).unwrap()
+    }
+
+    fn place() -> RfPlaceInfo {
+        serde_json::from_value(json!({
+            "name": "Озеро", "thumbnail": "", "featured_image": "", "payment_str": "", "payment_info": "",
+            "rating_str": "", "votes": 0, "contact_strs": [], "desc_short": "Рівненська обл., Рівне", "url": "", "id": 20,
+        })).unwrap()
+    }
+
+    fn route(v: serde_json::Value) -> Route {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn default_chat_if_nothing_matches() {
+        let routes = vec![route(json!({"chats": ["@carp"], "fishes": [12]}))];
+        assert_eq!(route_report(&routes, &report(), Some(&place()), "@home"), vec!["@home"]);
+        assert_eq!(route_report(&[], &report(), None, "@home"), vec!["@home"]);
+    }
+
+    #[test]
+    fn all_lists_must_match() {
+        let r = route(json!({"chats": ["@c"], "places": [20], "fishes": [3]}));
+        assert!(r.matches(&report(), None));
+
+        let r = route(json!({"chats": ["@c"], "places": [20], "fishes": [4]}));
+        assert!(!r.matches(&report(), None));
+
+        let r = route(json!({"chats": ["@c"], "regions": ["рівне"], "fishing_types": ["фідер", "Поплавок"]}));
+        assert!(r.matches(&report(), Some(&place())));
+        assert!(!r.matches(&report(), None));
+    }
+
+    #[test]
+    fn chats_of_all_matching_rules() {
+        let routes = vec![
+            route(json!({"chats": ["@a", "@b"], "places": [20]})),
+            route(json!({"chats": ["@b", "@c"], "fishes": [3]})),
+            route(json!({"chats": ["@d"], "fishes": [4]})),
+        ];
+        assert_eq!(route_report(&routes, &report(), None, "@home"), vec!["@a", "@b", "@c"]);
+    }
+}
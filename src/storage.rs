@@ -0,0 +1,320 @@
+use rusqlite::{self, Connection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use {PostId, UserId};
+
+#[derive(Serialize, Clone)]
+pub struct VoteRecord {
+    pub post: PostId,
+    pub user: UserId,
+    pub reaction: String,
+    pub at: i64,
+    pub active: bool, // false when user took the vote back
+}
+
+#[derive(Serialize, Clone)]
+pub struct PostRecord {
+    pub message_id: PostId,
+    pub report_id: i32,
+    pub url: String,
+    pub published_at: i64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct UserRecord {
+    pub id: UserId,
+    pub name: String,
+    pub seen_at: i64,
+}
+
+// History of what bot did and saw. Unlike BotState this is append-mostly
+// and is meant to be queried later, not kept in memory as a whole.
+pub trait Storage: Send + Sync {
+    fn record_vote(&self, vote: &VoteRecord) -> Result<(), String>;
+    fn record_post(&self, post: &PostRecord) -> Result<(), String>;
+    fn record_user(&self, user: &UserRecord) -> Result<(), String>;
+    fn bump_counter(&self, name: &str) -> Result<i64, String>;
+    fn counters(&self) -> Result<HashMap<String, i64>, String>;
+    fn posts_between(&self, from: i64, to: i64) -> Result<Vec<PostRecord>, String>;
+    // votes on posts published in [from, to)
+    fn votes_between(&self, from: i64, to: i64) -> Result<Vec<VoteRecord>, String>;
+}
+
+pub fn open(path: &str) -> Result<Box<dyn Storage>, String> {
+    if path == ":memory:" {
+        Ok(Box::new(MemStorage::default()))
+    } else {
+        SqliteStorage::open(path).map(|s| Box::new(s) as Box<dyn Storage>)
+    }
+}
+
+#[derive(Default)]
+struct MemData {
+    votes: Vec<VoteRecord>,
+    posts: HashMap<PostId, PostRecord>,
+    users: HashMap<UserId, UserRecord>,
+    counters: HashMap<String, i64>,
+}
+
+#[derive(Default)]
+pub struct MemStorage {
+    data: Mutex<MemData>,
+}
+
+impl MemStorage {
+    fn with<T, F: FnOnce(&mut MemData) -> T>(&self, f: F) -> Result<T, String> {
+        self.data.lock()
+            .map(|mut guard| f(&mut *guard))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Storage for MemStorage {
+    fn record_vote(&self, vote: &VoteRecord) -> Result<(), String> {
+        self.with(|d| d.votes.push(vote.clone()))
+    }
+
+    fn record_post(&self, post: &PostRecord) -> Result<(), String> {
+        self.with(|d| { d.posts.insert(post.message_id, post.clone()); })
+    }
+
+    fn record_user(&self, user: &UserRecord) -> Result<(), String> {
+        self.with(|d| { d.users.insert(user.id, user.clone()); })
+    }
+
+    fn bump_counter(&self, name: &str) -> Result<i64, String> {
+        self.with(|d| {
+            let c = d.counters.entry(name.to_owned()).or_insert(0);
+            *c += 1;
+            *c
+        })
+    }
+
+    fn counters(&self) -> Result<HashMap<String, i64>, String> {
+        self.with(|d| d.counters.clone())
+    }
+
+    fn posts_between(&self, from: i64, to: i64) -> Result<Vec<PostRecord>, String> {
+        self.with(|d| {
+            let mut ps = d.posts.values()
+                .filter(|p| p.published_at >= from && p.published_at < to)
+                .cloned()
+                .collect::<Vec<_>>();
+            ps.sort_by_key(|p| p.published_at);
+            ps
+        })
+    }
+
+    fn votes_between(&self, from: i64, to: i64) -> Result<Vec<VoteRecord>, String> {
+        self.with(|d| {
+            d.votes.iter()
+                .filter(|v| d.posts.get(&v.post)
+                    .map(|p| p.published_at >= from && p.published_at < to)
+                    .unwrap_or(false))
+                .cloned()
+                .collect()
+        })
+    }
+}
+
+// Each entry upgrades database by one version, current version is kept
+// in `user_version` pragma.
+const MIGRATIONS: &[&str] = &["
+CREATE TABLE IF NOT EXISTS votes (
+    post INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    at INTEGER NOT NULL,
+    active INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS votes_post ON votes (post);
+CREATE TABLE IF NOT EXISTS posts (
+    message_id INTEGER PRIMARY KEY,
+    report_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    published_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS posts_published_at ON posts (published_at);
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    seen_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS counters (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+", "
+ALTER TABLE votes ADD COLUMN reaction TEXT NOT NULL DEFAULT 'like';
+"];
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+fn db_err(e: rusqlite::Error) -> String {
+    e.to_string()
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(db_err)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", (), |r| r.get(0)).map_err(db_err)?;
+
+        for (i, m) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            conn.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", m, i + 1))
+                .map_err(db_err)?;
+            info!("upgraded storage to version {}", i + 1);
+        }
+
+        info!("opened storage at {}", path);
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn with<T, F>(&self, f: F) -> Result<T, String>
+        where F: FnOnce(&Connection) -> rusqlite::Result<T>,
+    {
+        match self.conn.lock() {
+            Ok(guard) => f(&*guard).map_err(db_err),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn record_vote(&self, vote: &VoteRecord) -> Result<(), String> {
+        self.with(|c| c.execute(
+            "INSERT INTO votes (post, user, reaction, at, active) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (vote.post, vote.user, &vote.reaction, vote.at, vote.active),
+        )).map(|_| ())
+    }
+
+    fn record_post(&self, post: &PostRecord) -> Result<(), String> {
+        self.with(|c| c.execute(
+            "INSERT OR REPLACE INTO posts (message_id, report_id, url, published_at) VALUES (?1, ?2, ?3, ?4)",
+            (post.message_id, post.report_id, &post.url, post.published_at),
+        )).map(|_| ())
+    }
+
+    fn record_user(&self, user: &UserRecord) -> Result<(), String> {
+        self.with(|c| c.execute(
+            "INSERT OR REPLACE INTO users (id, name, seen_at) VALUES (?1, ?2, ?3)",
+            (user.id, &user.name, user.seen_at),
+        )).map(|_| ())
+    }
+
+    fn bump_counter(&self, name: &str) -> Result<i64, String> {
+        self.with(|c| {
+            c.execute(
+                "INSERT INTO counters (name, value) VALUES (?1, 1) \
+                 ON CONFLICT (name) DO UPDATE SET value = value + 1",
+                (name,),
+            )?;
+            c.query_row("SELECT value FROM counters WHERE name = ?1", (name,), |r| r.get(0))
+        })
+    }
+
+    fn counters(&self) -> Result<HashMap<String, i64>, String> {
+        self.with(|c| {
+            let mut stmt = c.prepare("SELECT name, value FROM counters")?;
+            let rows = stmt.query_map((), |r| Ok((r.get(0)?, r.get(1)?)))?;
+            rows.collect()
+        })
+    }
+
+    fn posts_between(&self, from: i64, to: i64) -> Result<Vec<PostRecord>, String> {
+        self.with(|c| {
+            let mut stmt = c.prepare(
+                "SELECT message_id, report_id, url, published_at FROM posts \
+                 WHERE published_at >= ?1 AND published_at < ?2 ORDER BY published_at",
+            )?;
+            let rows = stmt.query_map((from, to), |r| Ok(PostRecord {
+                message_id: r.get(0)?,
+                report_id: r.get(1)?,
+                url: r.get(2)?,
+                published_at: r.get(3)?,
+            }))?;
+            rows.collect()
+        })
+    }
+
+    fn votes_between(&self, from: i64, to: i64) -> Result<Vec<VoteRecord>, String> {
+        self.with(|c| {
+            let mut stmt = c.prepare(
+                "SELECT v.post, v.user, v.reaction, v.at, v.active FROM votes v \
+                 JOIN posts p ON p.message_id = v.post \
+                 WHERE p.published_at >= ?1 AND p.published_at < ?2 ORDER BY v.at",
+            )?;
+            let rows = stmt.query_map((from, to), |r| Ok(VoteRecord {
+                post: r.get(0)?,
+                user: r.get(1)?,
+                reaction: r.get(2)?,
+                at: r.get(3)?,
+                active: r.get(4)?,
+            }))?;
+            rows.collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, json, Value};
+
+    fn exercise(db: &dyn Storage) -> Value {
+        for (id, at) in [(1, 100), (2, 200), (3, 300)].iter() {
+            db.record_post(&PostRecord { message_id: *id, report_id: *id * 10, url: format!("u{}", id), published_at: *at }).unwrap();
+        }
+        db.record_vote(&VoteRecord { post: 1, user: 5, reaction: "like".to_owned(), at: 110, active: true }).unwrap();
+        db.record_vote(&VoteRecord { post: 2, user: 5, reaction: "go".to_owned(), at: 210, active: true }).unwrap();
+        db.record_vote(&VoteRecord { post: 2, user: 5, reaction: "go".to_owned(), at: 220, active: false }).unwrap();
+        db.record_user(&UserRecord { id: 5, name: "user".to_owned(), seen_at: 220 }).unwrap();
+        db.bump_counter("published").unwrap();
+
+        json!({
+            "counter": db.bump_counter("published").unwrap(),
+            "counters": db.counters().unwrap(),
+            "posts": serde_json::to_value(db.posts_between(100, 300).unwrap()).unwrap(),
+            "votes": serde_json::to_value(db.votes_between(200, 300).unwrap()).unwrap(),
+        })
+    }
+
+    #[test]
+    fn sqlite_matches_memory() {
+        let mem = exercise(&MemStorage::default());
+        let sqlite = exercise(&SqliteStorage::open(":memory:").unwrap());
+
+        assert_eq!(mem["counter"], json!(2));
+        assert_eq!(mem["posts"].as_array().unwrap().len(), 2);
+        assert_eq!(mem["votes"].as_array().unwrap().len(), 2);
+        assert_eq!(mem, sqlite);
+    }
+
+    #[test]
+    fn sqlite_migrates_old_database() {
+        let path = ::std::env::temp_dir().join(format!("rvfish_storage_{}.sqlite", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = ::std::fs::remove_file(path);
+
+        {
+            let conn = Connection::open(path).unwrap();
+            conn.execute_batch(&format!("{} PRAGMA user_version = 1;", MIGRATIONS[0])).unwrap();
+            conn.execute_batch("INSERT INTO posts VALUES (1, 10, 'u', 100); INSERT INTO votes VALUES (1, 5, 110, 1);").unwrap();
+        }
+
+        let db = SqliteStorage::open(path).unwrap();
+        let votes = db.votes_between(0, 1000).unwrap();
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].reaction, "like");
+        let version: i64 = db.with(|c| c.query_row("PRAGMA user_version", (), |r| r.get(0))).unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        drop(db);
+        // reopening up to date database changes nothing
+        assert_eq!(SqliteStorage::open(path).unwrap().votes_between(0, 1000).unwrap().len(), 1);
+        let _ = ::std::fs::remove_file(path);
+    }
+}
@@ -2,6 +2,7 @@ use reqwest;
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 use serde_json;
+use serde_json::Value;
 use std::io::Read;
 use std::fmt::Write;
 
@@ -61,6 +62,19 @@ pub struct TgMessage {
 pub struct TgMessageLite {
     pub message_id: i32,
     pub chat: TgChat,
+    #[serde(default)]
+    pub photo: Vec<TgPhotoSize>, // smallest to largest
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TgPhotoSize {
+    pub file_id: String,
+}
+
+// Photo to send: URL or file_id of photo Telegram already has, or file contents to upload.
+pub enum TgInputFile {
+    Ref(String),
+    Bytes(Vec<u8>),
 }
 
 #[derive(Deserialize, Debug)]
@@ -85,6 +99,7 @@ pub struct TgCallbackQuery {
     pub id: String,
     pub from: TgUser,
     pub message: Option<TgMessageLite>,
+    pub inline_message_id: Option<String>,
     pub data: Option<String>,
 }
 
@@ -104,6 +119,7 @@ pub struct TgSendMsg {
     #[serde(skip_serializing_if = "Option::is_none")] parse_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")] reply_to_message_id: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")] reply_markup: Option<TgInlineKeyboardMarkup>,
+    #[serde(skip_serializing_if = "Option::is_none")] disable_notification: Option<bool>,
 }
 
 
@@ -111,6 +127,8 @@ pub struct TgSendMsg {
 struct TgInputMediaPhoto {
     #[serde(rename = "type")] type_: String,
     media: String,
+    #[serde(skip_serializing_if = "Option::is_none")] caption: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] parse_mode: Option<String>,
 }
 
 impl TgInputMediaPhoto {
@@ -118,6 +136,8 @@ impl TgInputMediaPhoto {
         Self {
             type_: "photo".to_owned(),
             media: String::from(url),
+            caption: None,
+            parse_mode: None,
         }
     }
 }
@@ -126,6 +146,7 @@ impl TgInputMediaPhoto {
 struct TgSendMediaGroup {
     chat_id: TgChatId,
     media: Vec<TgInputMediaPhoto>,
+    #[serde(skip_serializing_if = "Option::is_none")] disable_notification: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -135,6 +156,42 @@ pub struct TgEditMsgReplyMarkup {
     #[serde(skip_serializing_if = "Option::is_none")] reply_markup: Option<TgInlineKeyboardMarkup>,
 }
 
+#[derive(Serialize)]
+pub struct TgEditMsgText {
+    chat_id: TgChatId,
+    message_id: i32,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")] parse_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] reply_markup: Option<TgInlineKeyboardMarkup>,
+}
+
+#[derive(Serialize)]
+pub struct TgEditMsgCaption {
+    chat_id: TgChatId,
+    message_id: i32,
+    caption: String,
+    #[serde(skip_serializing_if = "Option::is_none")] parse_mode: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TgPinMsg {
+    chat_id: TgChatId,
+    message_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")] disable_notification: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct TgDeleteMsg {
+    chat_id: TgChatId,
+    message_id: i32,
+}
+
+#[derive(Serialize)]
+pub struct TgEditInlineMsgReplyMarkup {
+    inline_message_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")] reply_markup: Option<TgInlineKeyboardMarkup>,
+}
+
 #[derive(Serialize)]
 pub struct TgAnswerCBQ {
     callback_query_id: String,
@@ -203,6 +260,7 @@ pub struct TgChosenInlineResult {
 
 const BASEURL: &str = "https://api.telegram.org";
 const MAX_ALBUM_SIZE: usize = 10;
+pub const MAX_CAPTION_LEN: usize = 1024;
 
 pub struct TgBotApi<'a> {
     api_token: &'a str,
@@ -282,11 +340,12 @@ impl<'a> TgBotApi<'a> {
                 parse_mode: None,
                 reply_to_message_id: None,
                 reply_markup: None,
+                disable_notification: None,
             },
         );
     }
 
-    pub fn send_rich_text(&self, text: String, chatid: TgChatId, kb: Option<TgInlineKeyboardMarkup>)
+    pub fn send_rich_text(&self, text: String, chatid: TgChatId, kb: Option<TgInlineKeyboardMarkup>, silent: bool)
     -> Result<TgResponse<TgMessageLite>, String> {
         self.send_json_recv_json(
             "/sendMessage",
@@ -296,6 +355,22 @@ impl<'a> TgBotApi<'a> {
                 parse_mode: Some("HTML".to_owned()),
                 reply_to_message_id: None,
                 reply_markup: kb,
+                disable_notification: if silent { Some(true) } else { None },
+            },
+        )
+    }
+
+    pub fn send_rich_reply(&self, text: String, mid: i32, chatid: TgChatId, kb: Option<TgInlineKeyboardMarkup>, silent: bool)
+    -> Result<TgResponse<TgMessageLite>, String> {
+        self.send_json_recv_json(
+            "/sendMessage",
+            TgSendMsg {
+                chat_id: chatid,
+                text: text,
+                parse_mode: Some("HTML".to_owned()),
+                reply_to_message_id: Some(mid),
+                reply_markup: kb,
+                disable_notification: if silent { Some(true) } else { None },
             },
         )
     }
@@ -310,19 +385,75 @@ impl<'a> TgBotApi<'a> {
                 parse_mode: Some("Markdown".to_owned()),
                 reply_to_message_id: None,
                 reply_markup: kb,
+                disable_notification: None,
             },
         )
     }
 
-    pub fn send_album<'u, I: Iterator<Item=&'u String>>(&self, urls: I, chatid: TgChatId)
+    pub fn send_album<'u, I: Iterator<Item=&'u String>>(&self, urls: I, chatid: TgChatId, silent: bool)
     -> Result<TgResponse<Vec<TgMessageLite>>, String> {
-        self.send_json_recv_json(
-            "/sendMediaGroup",
-            TgSendMediaGroup {
-                chat_id: chatid,
-                media: urls.map(|url| TgInputMediaPhoto::new(url)).take(MAX_ALBUM_SIZE).collect(),
+        self.send_media_group(urls.map(|url| TgInputFile::Ref(url.clone())).collect(), None, chatid, silent)
+    }
+
+    // HTML caption is shown under the whole album when attached to its first photo.
+    pub fn send_media_group(&self, photos: Vec<TgInputFile>, caption: Option<String>, chatid: TgChatId, silent: bool)
+    -> Result<TgResponse<Vec<TgMessageLite>>, String> {
+        let mut media = Vec::new();
+        let mut files = Vec::new();
+        for (i, photo) in photos.into_iter().take(MAX_ALBUM_SIZE).enumerate() {
+            match photo {
+                TgInputFile::Ref(r) => media.push(TgInputMediaPhoto::new(&r)),
+                TgInputFile::Bytes(b) => {
+                    let name = format!("photo{}", i);
+                    media.push(TgInputMediaPhoto::new(&format!("attach://{}", name)));
+                    files.push((name, b));
+                },
             }
-        )
+        }
+
+        if let (Some(first), Some(caption)) = (media.first_mut(), caption) {
+            first.caption = Some(caption);
+            first.parse_mode = Some("HTML".to_owned());
+        }
+
+        let group = TgSendMediaGroup {
+            chat_id: chatid,
+            media: media,
+            disable_notification: if silent { Some(true) } else { None },
+        };
+
+        if files.is_empty() {
+            return self.send_json_recv_json("/sendMediaGroup", group);
+        }
+
+        // uploads go as multipart request, other fields become its text parts
+        let mut form = reqwest::multipart::Form::new();
+        match serde_json::to_value(&group) {
+            Ok(Value::Object(fields)) => for (k, v) in fields {
+                form = form.text(k, match v {
+                    Value::String(s) => s,
+                    v => v.to_string(),
+                });
+            },
+            Ok(_) => return Err("media group is not an object".to_owned()),
+            Err(e) => return Err(e.to_string()),
+        }
+        for (name, bytes) in files {
+            form = form.part(name.clone(), reqwest::multipart::Part::bytes(bytes).file_name(name));
+        }
+
+        self.send_multipart_recv_json("/sendMediaGroup", form)
+    }
+
+    pub fn send_multipart_recv_json<D: DeserializeOwned>(&self, method: &str, form: reqwest::multipart::Form)
+    -> Result<D, String> {
+        let url = format!("{}/bot{}{}", BASEURL, self.api_token, method);
+
+        self.http_client.post(&url)
+            .multipart(form)
+            .send()
+            .map_err(|e| e.to_string())
+            .and_then(|resp| serde_json::from_reader(resp).map_err(|e| e.to_string()))
     }
 
     pub fn send_reply(&self, text: String, mid: i32, chatid: TgChatId) {
@@ -334,6 +465,7 @@ impl<'a> TgBotApi<'a> {
                 parse_mode: None,
                 reply_to_message_id: Some(mid),
                 reply_markup: None,
+                disable_notification: None,
             },
         )
     }
@@ -348,6 +480,7 @@ impl<'a> TgBotApi<'a> {
                 parse_mode: None,
                 reply_to_message_id: None,
                 reply_markup: Some(kb),
+                disable_notification: None,
             },
         )
     }
@@ -362,6 +495,74 @@ impl<'a> TgBotApi<'a> {
             },
         );
     }
+
+    pub fn edit_rich_text(&self, msgid: i32, text: String, chatid: TgChatId, kb: Option<TgInlineKeyboardMarkup>)
+    -> Result<TgResponse<TgMessageLite>, String> {
+        self.send_json_recv_json(
+            "/editMessageText",
+            TgEditMsgText {
+                chat_id: chatid,
+                message_id: msgid,
+                text: text,
+                parse_mode: Some("HTML".to_owned()),
+                reply_markup: kb,
+            },
+        )
+    }
+
+    pub fn edit_caption(&self, msgid: i32, caption: String, chatid: TgChatId) -> Result<TgResponse<TgMessageLite>, String> {
+        self.send_json_recv_json(
+            "/editMessageCaption",
+            TgEditMsgCaption {
+                chat_id: chatid,
+                message_id: msgid,
+                caption: caption,
+                parse_mode: Some("HTML".to_owned()),
+            },
+        )
+    }
+
+    pub fn pin_msg(&self, msgid: i32, chatid: TgChatId, silent: bool) -> Result<TgResponse<bool>, String> {
+        self.send_json_recv_json(
+            "/pinChatMessage",
+            TgPinMsg {
+                chat_id: chatid,
+                message_id: msgid,
+                disable_notification: if silent { Some(true) } else { None },
+            },
+        )
+    }
+
+    pub fn unpin_msg(&self, msgid: i32, chatid: TgChatId) -> Result<TgResponse<bool>, String> {
+        self.send_json_recv_json(
+            "/unpinChatMessage",
+            TgPinMsg {
+                chat_id: chatid,
+                message_id: msgid,
+                disable_notification: None,
+            },
+        )
+    }
+
+    pub fn delete_msg(&self, msgid: i32, chatid: TgChatId) -> Result<TgResponse<bool>, String> {
+        self.send_json_recv_json(
+            "/deleteMessage",
+            TgDeleteMsg {
+                chat_id: chatid,
+                message_id: msgid,
+            },
+        )
+    }
+
+    pub fn update_inline_kb(&self, inline_msgid: String, kb: TgInlineKeyboardMarkup) {
+        self.send_json(
+            "/editMessageReplyMarkup",
+            TgEditInlineMsgReplyMarkup {
+                inline_message_id: inline_msgid,
+                reply_markup: Some(kb),
+            },
+        );
+    }
 }
 
 pub fn get_whoami(user: &TgUser, chat: &TgChat) -> String {
@@ -0,0 +1,190 @@
+use reqwest;
+use serde_json;
+use std::collections::BTreeMap;
+
+use fish::RfReportInfo;
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum TphNode {
+    Text(String),
+    Element(TphElement),
+}
+
+#[derive(Serialize)]
+pub struct TphElement {
+    tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")] attrs: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")] children: Vec<TphNode>,
+}
+
+impl TphNode {
+    fn elem(tag: &str, children: Vec<TphNode>) -> TphNode {
+        TphNode::Element(TphElement {
+            tag: tag.to_owned(),
+            attrs: None,
+            children,
+        })
+    }
+
+    fn elem_attr(tag: &str, attr: &str, value: &str, children: Vec<TphNode>) -> TphNode {
+        let mut attrs = BTreeMap::new();
+        attrs.insert(attr.to_owned(), value.to_owned());
+        TphNode::Element(TphElement {
+            tag: tag.to_owned(),
+            attrs: Some(attrs),
+            children,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct TphCreatePage<'a> {
+    access_token: &'a str,
+    title: &'a str,
+    author_name: &'a str,
+    author_url: &'a str,
+    content: &'a [TphNode],
+    return_content: bool,
+}
+
+#[derive(Deserialize)]
+struct TphPage {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct TphResponse<R> {
+    ok: bool,
+    result: Option<R>,
+    error: Option<String>,
+}
+
+pub struct TphApi {
+    url: String,
+    token: String,
+    http_client: reqwest::Client,
+}
+
+impl TphApi {
+    pub fn new(url: &str, token: &str) -> TphApi {
+        TphApi {
+            url: url.trim_end_matches('/').to_owned(),
+            token: token.to_owned(),
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    // Returns URL of created page.
+    pub fn create_page(&self, title: &str, author_name: &str, author_url: &str, content: &[TphNode]) -> Result<String, String> {
+        let bod = serde_json::to_string(&TphCreatePage {
+            access_token: &self.token,
+            title,
+            author_name,
+            author_url,
+            content,
+            return_content: false,
+        }).map_err(|e| e.to_string())?;
+
+        let r = self.http_client.post(&format!("{}/createPage", self.url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(bod)
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        match serde_json::from_reader::<reqwest::Response, TphResponse<TphPage>>(r) {
+            Ok(TphResponse {ok: true, result: Some(page), ..}) => Ok(page.url),
+            Ok(TphResponse {error, ..}) => Err(format!("Telegraph error: {:?}", error)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+// Full report text split into paragraphs, followed by all photos.
+pub fn report_content(report: &RfReportInfo) -> Vec<TphNode> {
+    let text = if report.description.trim().is_empty() {
+        &report.short_description
+    } else {
+        &report.description
+    };
+
+    let mut content = text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| TphNode::elem("p", vec![TphNode::Text(l.to_owned())]))
+        .collect::<Vec<_>>();
+
+    for photo in &report.photos {
+        content.push(TphNode::elem("figure", vec![TphNode::elem_attr("img", "src", &photo.medium_url, Vec::new())]));
+    }
+
+    content.push(TphNode::elem("p", vec![
+        TphNode::elem_attr("a", "href", &report.url, vec![TphNode::Text("Звіт на rivnefish.com".to_owned())]),
+    ]));
+
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use testutil;
+
+    fn report(description: &str) -> RfReportInfo {
+        serde_json::from_value(json!({
+            "id": 7,
+            "title": "Карась",
+            "short_description": "Коротко",
+            "description": description,
+            "url": "https://rivnefish.com/reports/7",
+            "photos": [{"medium_url": "https://rivnefish.com/1.jpg"}],
+            "start_at": "",
+            "fishing_types": [],
+            "report_fishes": [],
+        })).unwrap()
+    }
+
+    #[test]
+    fn report_content_has_paragraphs_and_photos() {
+        let content = serde_json::to_value(report_content(&report("Перший\n\n  Другий  \n"))).unwrap();
+        assert_eq!(content, json!([
+            {"tag": "p", "children": ["Перший"]},
+            {"tag": "p", "children": ["Другий"]},
+            {"tag": "figure", "children": [{"tag": "img", "attrs": {"src": "https://rivnefish.com/1.jpg"}}]},
+            {"tag": "p", "children": [
+                {"tag": "a", "attrs": {"href": "https://rivnefish.com/reports/7"}, "children": ["Звіт на rivnefish.com"]},
+            ]},
+        ]));
+    }
+
+    #[test]
+    fn create_page_posts_content() {
+        let (url, server) = testutil::serve(vec![(200, r#"{"ok": true, "result": {"path": "x", "url": "https://telegra.ph/x"}}"#)]);
+        let tph = TphApi::new(&format!("{}/", url), "token");
+        let content = vec![TphNode::elem("p", vec![TphNode::Text("text".to_owned())])];
+
+        assert_eq!(tph.create_page("title", "author", "https://a", &content), Ok("https://telegra.ph/x".to_owned()));
+
+        let bodies = server.join().unwrap();
+        let sent: serde_json::Value = serde_json::from_str(&bodies[0]).unwrap();
+        assert_eq!(sent, json!({
+            "access_token": "token",
+            "title": "title",
+            "author_name": "author",
+            "author_url": "https://a",
+            "content": [{"tag": "p", "children": ["text"]}],
+            "return_content": false,
+        }));
+    }
+
+    #[test]
+    fn create_page_reports_error() {
+        let (url, server) = testutil::serve(vec![(200, r#"{"ok": false, "error": "ACCESS_TOKEN_INVALID"}"#)]);
+        let tph = TphApi::new(&url, "bad");
+
+        let res = tph.create_page("title", "author", "https://a", &[]);
+        assert_eq!(res, Err("Telegraph error: Some(\"ACCESS_TOKEN_INVALID\")".to_owned()));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+use {Config, Layout};
+
+// Local stand-in HTTP server: answers requests one by one with given
+// (status, body) pairs and hands back bodies of requests it got.
+pub fn serve(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = thread::spawn(move || {
+        let mut bodies = Vec::new();
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                    len = v.trim().parse().unwrap();
+                }
+            }
+            let mut buf = vec![0; len];
+            reader.read_exact(&mut buf).unwrap();
+            bodies.push(String::from_utf8(buf).unwrap());
+
+            write!(stream, "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body).unwrap();
+        }
+        bodies
+    });
+
+    (url, handle)
+}
+
+// Settings for tests, independent of RVFISH_* variables of the environment.
+pub fn config() -> Config {
+    Config {
+        bottoken: "123:token".to_owned(),
+        channel: "@channel".to_owned(),
+        listenpath: "/bot".to_owned(),
+        listenaddr: "localhost:2358".to_owned(),
+        layout: Layout::Card,
+        snapshot: String::new(),
+        statefile: String::new(),
+        savedelay: 5,
+        database: ":memory:".to_owned(),
+        retention: 0,
+        archive: String::new(),
+        shutdowntimeout: 10,
+        cbqsecret: "secret".to_owned(),
+        cbqunsigneduntil: None,
+        kbeditdelay: 2000,
+        votecooldown: 1000,
+        weeklytop: false,
+        weeklytopday: 0,
+        weeklytophour: 18,
+        weeklytopsize: 5,
+        inlinevotes: false,
+        votesyncurl: String::new(),
+        votesynctoken: String::new(),
+        votesyncperiod: 600,
+        quiethours: None,
+        silenthours: None,
+        digestperiod: 0,
+        digestonly: false,
+        digestday: 0,
+        digesthour: 20,
+        routes: Vec::new(),
+        retrydelay: 60,
+        retryattempts: 5,
+        pollperiod: 0,
+        testchat: String::new(),
+        pinfeatured: false,
+        telegraphurl: String::new(),
+        telegraphtoken: String::new(),
+        telegraphminlen: 1000,
+    }
+}